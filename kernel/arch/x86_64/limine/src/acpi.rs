@@ -0,0 +1,138 @@
+//! Minimal ACPI table discovery.
+//!
+//! Walks the RSDP → RSDT/XSDT chain that Limine points us at and looks up
+//! tables by signature.  Table contents are left to the drivers that need
+//! them (MADT for the APICs, HPET for the timer).
+
+use core::mem::size_of;
+
+use crate::addr::{PhysAddr, VirtAddr};
+use crate::{mmio, rsdp_address};
+
+/// Common header shared by every ACPI system description table.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Root System Description Pointer (ACPI 2.0+ layout; ACPI 1.0 tables stop
+/// after `rsdt_address`).
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Size of the ACPI 1.0 part of the RSDP covered by `checksum`.
+const RSDP_V1_SIZE: usize = 20;
+
+/// A mapped ACPI table.
+#[derive(Clone, Copy)]
+pub struct Table {
+    /// Physical address of the table header.
+    pub phys: PhysAddr,
+    /// Virtual address of the table header.
+    pub virt: VirtAddr,
+    /// Total length in bytes, including the header.
+    pub length: usize,
+}
+
+impl Table {
+    /// The table's common header.
+    pub fn header(&self) -> SdtHeader {
+        // SAFETY: `virt` points at a mapped table of at least `length` bytes.
+        unsafe { core::ptr::read_unaligned(self.virt.as_ptr::<SdtHeader>()) }
+    }
+
+    /// Read a `T` at byte `offset` from the start of the table.
+    ///
+    /// # Safety
+    /// `offset + size_of::<T>()` must lie within the table.
+    pub unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { core::ptr::read_unaligned((self.virt + offset as u64).as_ptr::<T>()) }
+    }
+}
+
+/// ACPI checksums make the byte sum of the whole structure zero.
+fn checksum_ok(virt: VirtAddr, len: usize) -> bool {
+    // SAFETY: the caller mapped `len` bytes at `virt`.
+    let bytes = unsafe { core::slice::from_raw_parts(virt.as_ptr::<u8>(), len) };
+    bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) == 0
+}
+
+/// Map the table at `phys` (header first, to learn its length) and verify
+/// its checksum.
+fn map_table(phys: PhysAddr) -> Option<Table> {
+    // SAFETY: boot-time mapping of firmware-owned memory.
+    unsafe {
+        let virt = mmio::map_cached(phys, size_of::<SdtHeader>() as u64)?;
+        let header = core::ptr::read_unaligned(virt.as_ptr::<SdtHeader>());
+        let length = header.length as usize;
+        if length < size_of::<SdtHeader>() {
+            return None;
+        }
+        mmio::map_cached(phys, length as u64)?;
+        if !checksum_ok(virt, length) {
+            return None;
+        }
+        Some(Table { phys, virt, length })
+    }
+}
+
+/// Locate the RSDT or XSDT and report whether its entries are 64-bit.
+fn root_table() -> Option<(Table, bool)> {
+    let rsdp_phys = rsdp_address()?;
+
+    // SAFETY: boot-time mapping of firmware-owned memory.
+    let virt = unsafe { mmio::map_cached(rsdp_phys, size_of::<Rsdp>() as u64)? };
+    let rsdp = unsafe { core::ptr::read_unaligned(virt.as_ptr::<Rsdp>()) };
+    if &rsdp.signature != b"RSD PTR " || !checksum_ok(virt, RSDP_V1_SIZE) {
+        return None;
+    }
+
+    if rsdp.revision >= 2
+        && rsdp.xsdt_address != 0
+        && let Some(xsdt) = map_table(PhysAddr::new(rsdp.xsdt_address))
+    {
+        return Some((xsdt, true));
+    }
+    map_table(PhysAddr::new(rsdp.rsdt_address as u64)).map(|rsdt| (rsdt, false))
+}
+
+/// Find the first ACPI table with the given signature, e.g. `b"APIC"`.
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    let (root, wide) = root_table()?;
+    let entry_size = if wide { 8 } else { 4 };
+    let count = (root.length - size_of::<SdtHeader>()) / entry_size;
+
+    for i in 0..count {
+        let offset = size_of::<SdtHeader>() + i * entry_size;
+        // SAFETY: `offset` lies within the root table.
+        let phys = unsafe {
+            if wide { root.read::<u64>(offset) } else { root.read::<u32>(offset) as u64 }
+        };
+        if let Some(table) = map_table(PhysAddr::new(phys))
+            && &table.header().signature == signature
+        {
+            return Some(table);
+        }
+    }
+    None
+}
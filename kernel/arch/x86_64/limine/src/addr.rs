@@ -0,0 +1,210 @@
+//! Physical and virtual address types.
+//!
+//! [`PhysAddr`] and [`VirtAddr`] keep the two address spaces apart in
+//! signatures.  Physical memory is reached through Limine's higher-half
+//! direct map (HHDM): [`phys_to_virt`] adds the HHDM offset, and
+//! [`virt_to_phys`] undoes it, translates through the kernel image's load
+//! address for statics, or walks the page tables for anything else.
+//! [`init`] caches both offsets and the extent of the HHDM so conversions
+//! keep working after the bootloader's responses have been reclaimed.
+
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::paging::kernel_space;
+use crate::{executable_address, hhdm_offset, memmap_entries};
+
+/// Physical addresses are at most 52 bits wide.
+const PHYS_ADDR_MASK: u64 = (1 << 52) - 1;
+
+static HHDM_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Virtual address just past the HHDM: the offset plus the end of the
+/// highest memory-map entry.  The heap and kernel stacks lie above it.
+static HHDM_END: AtomicU64 = AtomicU64::new(0);
+static IMAGE_PHYS_BASE: AtomicU64 = AtomicU64::new(0);
+static IMAGE_VIRT_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// A physical memory address.
+#[repr(transparent)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysAddr(u64);
+
+/// A canonical virtual memory address.
+#[repr(transparent)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VirtAddr(u64);
+
+impl PhysAddr {
+    /// Wrap `addr`.
+    ///
+    /// # Panics
+    /// If `addr` does not fit in 52 bits.
+    pub const fn new(addr: u64) -> Self {
+        assert!(addr & !PHYS_ADDR_MASK == 0, "physical address wider than 52 bits");
+        Self(addr)
+    }
+
+    /// The raw address.
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Round down to a multiple of `align` (a power of two).
+    pub const fn align_down(self, align: u64) -> Self {
+        Self(self.0 & !(align - 1))
+    }
+
+    /// Round up to a multiple of `align` (a power of two).
+    pub const fn align_up(self, align: u64) -> Self {
+        Self::new((self.0 + align - 1) & !(align - 1))
+    }
+
+    /// Returns `true` if the address is a multiple of `align`.
+    pub const fn is_aligned(self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+}
+
+impl VirtAddr {
+    /// Wrap `addr`.
+    ///
+    /// # Panics
+    /// If `addr` is not canonical (bits 63:48 must equal bit 47).
+    pub const fn new(addr: u64) -> Self {
+        assert!(Self::new_truncate(addr).0 == addr, "non-canonical virtual address");
+        Self(addr)
+    }
+
+    /// Wrap `addr`, sign-extending bit 47 to make it canonical.
+    pub const fn new_truncate(addr: u64) -> Self {
+        Self((((addr << 16) as i64) >> 16) as u64)
+    }
+
+    /// The address of `ptr`.
+    pub fn from_ptr<T: ?Sized>(ptr: *const T) -> Self {
+        Self::new(ptr as *const () as u64)
+    }
+
+    /// The raw address.
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The address as a pointer.
+    pub const fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    /// The address as a mutable pointer.
+    pub const fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Round down to a multiple of `align` (a power of two).
+    pub const fn align_down(self, align: u64) -> Self {
+        Self(self.0 & !(align - 1))
+    }
+
+    /// Round up to a multiple of `align` (a power of two).
+    pub const fn align_up(self, align: u64) -> Self {
+        Self::new((self.0 + align - 1) & !(align - 1))
+    }
+
+    /// Returns `true` if the address is a multiple of `align`.
+    pub const fn is_aligned(self, align: u64) -> bool {
+        self.0 & (align - 1) == 0
+    }
+}
+
+macro_rules! impl_addr_ops {
+    ($ty:ident, $prefix:literal) => {
+        impl Add<u64> for $ty {
+            type Output = Self;
+
+            fn add(self, rhs: u64) -> Self {
+                Self::new(self.0 + rhs)
+            }
+        }
+
+        impl AddAssign<u64> for $ty {
+            fn add_assign(&mut self, rhs: u64) {
+                *self = *self + rhs;
+            }
+        }
+
+        impl Sub<u64> for $ty {
+            type Output = Self;
+
+            fn sub(self, rhs: u64) -> Self {
+                Self::new(self.0 - rhs)
+            }
+        }
+
+        /// Distance in bytes between two addresses.
+        impl Sub<$ty> for $ty {
+            type Output = u64;
+
+            fn sub(self, rhs: $ty) -> u64 {
+                self.0 - rhs.0
+            }
+        }
+
+        impl fmt::Debug for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, concat!($prefix, "({:#x})"), self.0)
+            }
+        }
+    };
+}
+
+impl_addr_ops!(PhysAddr, "PhysAddr");
+impl_addr_ops!(VirtAddr, "VirtAddr");
+
+/// Cache the HHDM offset and the kernel's load address.
+///
+/// # Safety
+/// Must be called during boot, while the bootloader responses are still
+/// intact, before any other function in this module.
+pub unsafe fn init() {
+    if let Some(offset) = hhdm_offset() {
+        HHDM_OFFSET.store(offset, Ordering::Relaxed);
+        let phys_end = memmap_entries()
+            .and_then(|entries| entries.map(|entry| entry.base + entry.length).max())
+            .unwrap_or(0);
+        HHDM_END.store(offset + phys_end, Ordering::Relaxed);
+    }
+    if let Some((phys, virt)) = executable_address() {
+        IMAGE_PHYS_BASE.store(phys.as_u64(), Ordering::Relaxed);
+        IMAGE_VIRT_BASE.store(virt.as_u64(), Ordering::Relaxed);
+    }
+}
+
+/// Virtual address at which the HHDM maps `phys`.
+///
+/// Since base revision 3 the HHDM only covers memory-map entries; device
+/// memory has to be mapped first (see [`crate::mmio`]).
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(HHDM_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+/// Physical address behind `virt`, if it is mapped.
+///
+/// Addresses in the kernel image and the HHDM are translated by offset;
+/// anything else (the heap, kernel stacks, device memory mapped above the
+/// HHDM) by walking the kernel's page tables, which takes the
+/// [`kernel_space`] lock.
+pub fn virt_to_phys(virt: VirtAddr) -> Option<PhysAddr> {
+    let image_virt = IMAGE_VIRT_BASE.load(Ordering::Relaxed);
+    let hhdm = HHDM_OFFSET.load(Ordering::Relaxed);
+    let hhdm_end = HHDM_END.load(Ordering::Relaxed);
+
+    // The image sits in the top 2 GiB, above everything else.
+    if image_virt != 0 && virt.as_u64() >= image_virt {
+        Some(PhysAddr::new(virt.as_u64() - image_virt + IMAGE_PHYS_BASE.load(Ordering::Relaxed)))
+    } else if hhdm != 0 && (hhdm..hhdm_end).contains(&virt.as_u64()) {
+        Some(PhysAddr::new(virt.as_u64() - hhdm))
+    } else {
+        kernel_space().translate(virt).map(|translation| translation.phys)
+    }
+}
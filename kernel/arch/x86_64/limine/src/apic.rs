@@ -0,0 +1,542 @@
+//! Local APIC and I/O APIC driver.
+//!
+//! The APIC topology is read from the ACPI MADT.  ISA IRQs 0-15 are routed
+//! through the I/O APIC to the same vectors the 8259 uses (0x20-0x2F),
+//! honouring the MADT's interrupt source overrides, so drivers see no
+//! difference between the two controllers.  GSIs 16-23 are exposed as
+//! IRQ 16-23 on vectors 0x30-0x37.  The API mirrors [`crate::pic`].
+//!
+//! The local APIC timer, calibrated against the TSC, gives the APs the
+//! scheduler tick that the PIT gives the BSP.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::acpi;
+use crate::addr::{PhysAddr, VirtAddr};
+use crate::irq::{IRQ_BASE_VECTOR, IRQ_COUNT, ISA_IRQ_COUNT};
+use crate::mmio;
+use crate::msr::{rdmsr, wrmsr};
+use crate::thread;
+use crate::tsc;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+// Local APIC register offsets (xAPIC MMIO layout).
+const LAPIC_ID: u32 = 0x20;
+const LAPIC_TPR: u32 = 0x80;
+const LAPIC_EOI: u32 = 0xB0;
+const LAPIC_SVR: u32 = 0xF0;
+const LAPIC_ISR: u32 = 0x100;
+const LAPIC_ICR_LOW: u32 = 0x300;
+const LAPIC_ICR_HIGH: u32 = 0x310;
+const LAPIC_LVT_TIMER: u32 = 0x320;
+const LAPIC_TIMER_INITIAL: u32 = 0x380;
+const LAPIC_TIMER_CURRENT: u32 = 0x390;
+const LAPIC_TIMER_DIVIDE: u32 = 0x3E0;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Timer divide configuration value for a divisor of 16.
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+const TIMER_CALIBRATION_MS: u64 = 10;
+
+/// Vector the local APIC uses for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector of the local APIC timer.
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+// I/O APIC registers.
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION: u32 = 0x10;
+
+const REDIR_ACTIVE_LOW: u64 = 1 << 13;
+const REDIR_LEVEL: u64 = 1 << 15;
+const REDIR_MASKED: u64 = 1 << 16;
+
+// MADT entry types.
+const MADT_IOAPIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LAPIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Offset of the first MADT entry (after the SDT header, LAPIC address and
+/// flags).
+const MADT_ENTRIES_OFFSET: usize = 44;
+
+const MAX_IOAPICS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    virt: VirtAddr,
+    gsi_base: u32,
+    redirection_count: u32,
+}
+
+/// GSI of an ISA IRQ that has no I/O APIC pin of its own.
+const NO_GSI: u32 = u32::MAX;
+
+/// Where an IRQ ends up on the I/O APIC.
+#[derive(Clone, Copy)]
+struct IrqRoute {
+    gsi: u32,
+    active_low: bool,
+    level: bool,
+}
+
+struct ApicState {
+    lapic_virt: VirtAddr,
+    x2apic: bool,
+    ioapics: [IoApic; MAX_IOAPICS],
+    ioapic_count: usize,
+    routes: [IrqRoute; IRQ_COUNT],
+}
+
+/// APIC state, wrapped in UnsafeCell so we can initialise it once during boot.
+struct ApicCell(UnsafeCell<ApicState>);
+unsafe impl Sync for ApicCell {}
+
+static STATE: ApicCell = ApicCell(UnsafeCell::new(ApicState {
+    lapic_virt: VirtAddr::new(0),
+    x2apic: false,
+    ioapics: [IoApic { virt: VirtAddr::new(0), gsi_base: 0, redirection_count: 0 }; MAX_IOAPICS],
+    ioapic_count: 0,
+    routes: [IrqRoute { gsi: 0, active_low: false, level: false }; IRQ_COUNT],
+}));
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Timer initial count for one interrupt per tick; zero until
+/// [`calibrate_timer`] has run.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn state() -> &'static ApicState {
+    // SAFETY: only mutated by `init`, before ENABLED is set.
+    unsafe { &*STATE.0.get() }
+}
+
+// ── Spurious interrupt stub ─────────────────────────────────────────
+
+// Spurious APIC interrupts must not be acknowledged; just return.
+core::arch::global_asm!(
+    ".global _apic_spurious_stub",
+    "_apic_spurious_stub:",
+    "iretq",
+);
+
+unsafe extern "C" {
+    fn _apic_spurious_stub();
+}
+
+/// Address of the handler for [`SPURIOUS_VECTOR`].
+pub fn spurious_stub_address() -> u64 {
+    _apic_spurious_stub as *const () as u64
+}
+
+// ── Timer interrupt stub ────────────────────────────────────────────
+
+// Enter the common path like an IRQ would.
+core::arch::global_asm!(
+    ".global _apic_timer_stub",
+    "_apic_timer_stub:",
+    "push 0",
+    "push {vector}",
+    "jmp _isr_common",
+    vector = const TIMER_VECTOR,
+);
+
+unsafe extern "C" {
+    fn _apic_timer_stub();
+}
+
+/// Address of the handler for [`TIMER_VECTOR`].
+pub fn timer_stub_address() -> u64 {
+    _apic_timer_stub as *const () as u64
+}
+
+// ── Local APIC ──────────────────────────────────────────────────────
+
+unsafe fn lapic_read(reg: u32) -> u32 {
+    let s = state();
+    unsafe {
+        if s.x2apic {
+            rdmsr(0x800 + (reg >> 4)) as u32
+        } else {
+            core::ptr::read_volatile((s.lapic_virt + reg as u64).as_ptr::<u32>())
+        }
+    }
+}
+
+unsafe fn lapic_write(reg: u32, value: u32) {
+    let s = state();
+    unsafe {
+        if s.x2apic {
+            wrmsr(0x800 + (reg >> 4), value as u64);
+        } else {
+            core::ptr::write_volatile((s.lapic_virt + reg as u64).as_mut_ptr::<u32>(), value);
+        }
+    }
+}
+
+/// APIC ID of the calling CPU.
+pub fn lapic_id() -> u32 {
+    // SAFETY: the LAPIC is mapped once `init` has succeeded.
+    let id = unsafe { lapic_read(LAPIC_ID) };
+    if state().x2apic { id } else { id >> 24 }
+}
+
+/// Software-enable the calling CPU's local APIC and accept all priorities.
+///
+/// # Safety
+/// [`init`] must have succeeded.
+pub unsafe fn enable_local() {
+    unsafe {
+        lapic_write(LAPIC_TPR, 0);
+        lapic_write(LAPIC_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+}
+
+/// Enable the local APIC of an application processor, in the same mode
+/// (xAPIC or x2APIC) as the BSP's.
+///
+/// # Safety
+/// [`init`] must have succeeded on the BSP; call once per AP.
+pub unsafe fn init_ap() {
+    unsafe {
+        let mut apic_base = rdmsr(IA32_APIC_BASE) | APIC_BASE_ENABLE;
+        if state().x2apic {
+            apic_base |= APIC_BASE_X2APIC;
+        }
+        wrmsr(IA32_APIC_BASE, apic_base);
+        enable_local();
+    }
+}
+
+/// Send a fixed interrupt on `vector` to the CPU with APIC ID `lapic_id`.
+///
+/// # Safety
+/// [`init`] must have succeeded, and the target must handle `vector`.
+pub unsafe fn send_ipi(lapic_id: u32, vector: u8) {
+    let low = ICR_LEVEL_ASSERT | vector as u32;
+    unsafe {
+        if state().x2apic {
+            // x2APIC has a single 64-bit ICR and no delivery status bit.
+            wrmsr(0x800 + (LAPIC_ICR_LOW >> 4), ((lapic_id as u64) << 32) | low as u64);
+        } else {
+            lapic_write(LAPIC_ICR_HIGH, lapic_id << 24);
+            lapic_write(LAPIC_ICR_LOW, low);
+            while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+// ── I/O APIC ────────────────────────────────────────────────────────
+
+unsafe fn ioapic_read(ioapic: &IoApic, reg: u32) -> u32 {
+    unsafe {
+        core::ptr::write_volatile((ioapic.virt + IOAPIC_REGSEL).as_mut_ptr::<u32>(), reg);
+        core::ptr::read_volatile((ioapic.virt + IOAPIC_WINDOW).as_ptr::<u32>())
+    }
+}
+
+unsafe fn ioapic_write(ioapic: &IoApic, reg: u32, value: u32) {
+    unsafe {
+        core::ptr::write_volatile((ioapic.virt + IOAPIC_REGSEL).as_mut_ptr::<u32>(), reg);
+        core::ptr::write_volatile((ioapic.virt + IOAPIC_WINDOW).as_mut_ptr::<u32>(), value);
+    }
+}
+
+/// The I/O APIC serving `gsi` and the pin number on it.
+fn ioapic_for_gsi(gsi: u32) -> Option<(&'static IoApic, u32)> {
+    let s = state();
+    s.ioapics[..s.ioapic_count]
+        .iter()
+        .find(|io| gsi >= io.gsi_base && gsi < io.gsi_base + io.redirection_count)
+        .map(|io| (io, gsi - io.gsi_base))
+}
+
+unsafe fn read_redirection(ioapic: &IoApic, pin: u32) -> u64 {
+    unsafe {
+        let low = ioapic_read(ioapic, IOAPIC_REDIRECTION + pin * 2) as u64;
+        let high = ioapic_read(ioapic, IOAPIC_REDIRECTION + pin * 2 + 1) as u64;
+        (high << 32) | low
+    }
+}
+
+unsafe fn write_redirection(ioapic: &IoApic, pin: u32, entry: u64) {
+    unsafe {
+        // Write the high half first so the entry never points at a stale
+        // destination while unmasked.
+        ioapic_write(ioapic, IOAPIC_REDIRECTION + pin * 2 + 1, (entry >> 32) as u32);
+        ioapic_write(ioapic, IOAPIC_REDIRECTION + pin * 2, entry as u32);
+    }
+}
+
+/// Set or clear the mask bit of the redirection entry for `irq`.
+unsafe fn set_masked(irq: u8, masked: bool) {
+    let Some(route) = state().routes.get(irq as usize) else { return };
+    let Some((ioapic, pin)) = ioapic_for_gsi(route.gsi) else { return };
+    unsafe {
+        let mut entry = read_redirection(ioapic, pin);
+        if masked {
+            entry |= REDIR_MASKED;
+        } else {
+            entry &= !REDIR_MASKED;
+        }
+        write_redirection(ioapic, pin, entry);
+    }
+}
+
+// ── MADT parsing ────────────────────────────────────────────────────
+
+/// Decode MPS INTI flags, where bus-default means ISA: active high, edge.
+fn route_from_flags(gsi: u32, flags: u16) -> IrqRoute {
+    IrqRoute {
+        gsi,
+        active_low: flags & 0x3 == 0x3,
+        level: (flags >> 2) & 0x3 == 0x3,
+    }
+}
+
+/// Fill `state` from the MADT.  Returns `false` if there is no usable
+/// I/O APIC.
+fn parse_madt(state: &mut ApicState) -> bool {
+    let Some(madt) = acpi::find_table(b"APIC") else { return false };
+
+    // SAFETY: all reads below are bounds-checked against the table length.
+    unsafe {
+        let mut lapic_phys = PhysAddr::new(madt.read::<u32>(36) as u64);
+
+        // Identity-route every IRQ until an override says otherwise.  GSIs
+        // 16-23 have no ISA default; active-high edge suits the on-board
+        // devices (HPET) that use them.
+        for (irq, route) in state.routes.iter_mut().enumerate() {
+            *route = route_from_flags(irq as u32, 0);
+        }
+
+        let mut offset = MADT_ENTRIES_OFFSET;
+        while offset + 2 <= madt.length {
+            let kind = madt.read::<u8>(offset);
+            let len = madt.read::<u8>(offset + 1) as usize;
+            if len < 2 || offset + len > madt.length {
+                break;
+            }
+
+            match kind {
+                MADT_IOAPIC if len >= 12 && state.ioapic_count < MAX_IOAPICS => {
+                    let phys = PhysAddr::new(madt.read::<u32>(offset + 4) as u64);
+                    let gsi_base = madt.read::<u32>(offset + 8);
+                    if let Some(virt) = mmio::map_uncached(phys, 0x20) {
+                        let mut ioapic = IoApic { virt, gsi_base, redirection_count: 0 };
+                        ioapic.redirection_count = ((ioapic_read(&ioapic, IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+                        state.ioapics[state.ioapic_count] = ioapic;
+                        state.ioapic_count += 1;
+                    }
+                }
+                MADT_INTERRUPT_OVERRIDE if len >= 10 => {
+                    let source = madt.read::<u8>(offset + 3) as usize;
+                    let gsi = madt.read::<u32>(offset + 4);
+                    let flags = madt.read::<u16>(offset + 8);
+                    if source < ISA_IRQ_COUNT {
+                        state.routes[source] = route_from_flags(gsi, flags);
+                    }
+                }
+                MADT_LAPIC_ADDRESS_OVERRIDE if len >= 12 => {
+                    lapic_phys = PhysAddr::new(madt.read::<u64>(offset + 4));
+                }
+                _ => {}
+            }
+
+            offset += len;
+        }
+
+        // An override moves its source onto another IRQ's pin (typically
+        // IRQ0 onto GSI 2); the displaced IRQ must not claim that pin too.
+        for irq in 0..IRQ_COUNT {
+            let overridden = state.routes.iter().enumerate()
+                .any(|(source, route)| source != irq && route.gsi == irq as u32);
+            if overridden && state.routes[irq].gsi == irq as u32 {
+                state.routes[irq].gsi = NO_GSI;
+            }
+        }
+
+        if state.ioapic_count == 0 {
+            return false;
+        }
+
+        let apic_base = rdmsr(IA32_APIC_BASE);
+        state.x2apic = apic_base & APIC_BASE_X2APIC != 0;
+        if !state.x2apic {
+            // Prefer the address the CPU itself reports.
+            if apic_base & APIC_BASE_ADDR_MASK != 0 {
+                lapic_phys = PhysAddr::new(apic_base & APIC_BASE_ADDR_MASK);
+            }
+            match mmio::map_uncached(lapic_phys, 0x400) {
+                Some(virt) => state.lapic_virt = virt,
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+// ── Local APIC timer ────────────────────────────────────────────────
+
+/// Count the local APIC timer's ticks across `TIMER_CALIBRATION_MS` of
+/// TSC time, and work out the initial count for `hz` interrupts a second.
+/// The timer runs at the same rate on every CPU, so this is done once.
+/// Does nothing if the APIC is not in use or the TSC is uncalibrated.
+///
+/// # Safety
+/// Must be called once on the BSP, after [`init`] and [`tsc::init`], with
+/// interrupts off.
+pub unsafe fn calibrate_timer(hz: u32) {
+    let tsc_hz = tsc::frequency_hz();
+    if !is_enabled() || tsc_hz == 0 || hz == 0 {
+        return;
+    }
+    let cycles = tsc_hz * TIMER_CALIBRATION_MS / 1000;
+
+    let elapsed = unsafe {
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+        lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+        let start = tsc::rdtsc();
+        while tsc::rdtsc() - start < cycles {
+            core::hint::spin_loop();
+        }
+        let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+        lapic_write(LAPIC_TIMER_INITIAL, 0);
+        elapsed
+    };
+
+    let per_second = elapsed as u64 * 1000 / TIMER_CALIBRATION_MS;
+    let count = (per_second / hz as u64).clamp(1, u32::MAX as u64) as u32;
+    TIMER_INITIAL_COUNT.store(count, Ordering::Release);
+}
+
+/// Start the calling CPU's local APIC timer in periodic mode, at the rate
+/// chosen by [`calibrate_timer`].  Returns `false` if it was never
+/// calibrated.
+///
+/// # Safety
+/// [`init`] must have succeeded, the calling CPU's local APIC must be
+/// enabled, and the IDT must handle [`TIMER_VECTOR`].
+pub unsafe fn start_timer() -> bool {
+    let count = TIMER_INITIAL_COUNT.load(Ordering::Acquire);
+    if count == 0 {
+        return false;
+    }
+    unsafe {
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | TIMER_VECTOR as u32);
+        lapic_write(LAPIC_TIMER_INITIAL, count);
+    }
+    true
+}
+
+/// Called from the common interrupt path for [`TIMER_VECTOR`].
+pub fn handle_timer() {
+    // SAFETY: the timer is only started once the local APIC is enabled.
+    unsafe { send_eoi(0); }
+    thread::timer_tick();
+}
+
+// ── Public API (mirrors `pic`) ──────────────────────────────────────
+
+/// Returns `true` once the APICs have replaced the 8259 PIC.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Discover the APICs via the MADT, enable the local APIC on the calling
+/// CPU and route every IRQ (masked) to vectors 0x20-0x37.
+///
+/// Returns `false`, leaving the 8259 in charge, when no APIC is present.
+/// On success the caller must keep the 8259 fully masked.
+///
+/// # Safety
+/// Must be called once, during single-threaded boot, with interrupts off.
+pub unsafe fn init() -> bool {
+    // SAFETY: single-threaded init context; no other references exist.
+    if !parse_madt(unsafe { &mut *STATE.0.get() }) {
+        return false;
+    }
+
+    unsafe {
+        let apic_base = rdmsr(IA32_APIC_BASE);
+        wrmsr(IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+        enable_local();
+
+        let dest = lapic_id() as u64;
+        for (irq, route) in state().routes.iter().enumerate() {
+            let Some((ioapic, pin)) = ioapic_for_gsi(route.gsi) else { continue };
+
+            let mut entry = (IRQ_BASE_VECTOR as u64 + irq as u64) | REDIR_MASKED | (dest << 56);
+            if route.active_low {
+                entry |= REDIR_ACTIVE_LOW;
+            }
+            if route.level {
+                entry |= REDIR_LEVEL;
+            }
+            write_redirection(ioapic, pin, entry);
+        }
+    }
+
+    ENABLED.store(true, Ordering::Release);
+    true
+}
+
+/// Unmask (enable) IRQ `irq` (0-23) on the I/O APIC.
+///
+/// # Safety
+/// [`init`] must have succeeded.
+pub unsafe fn unmask_irq(irq: u8) {
+    unsafe { set_masked(irq, false); }
+}
+
+/// Mask (disable) IRQ `irq` (0-23) on the I/O APIC.
+///
+/// # Safety
+/// [`init`] must have succeeded.
+pub unsafe fn mask_irq(irq: u8) {
+    unsafe { set_masked(irq, true); }
+}
+
+/// The IRQ delivered for `gsi`, if it has one.
+pub fn irq_for_gsi(gsi: u32) -> Option<u8> {
+    if !is_enabled() {
+        return None;
+    }
+    state().routes.iter().position(|route| route.gsi == gsi).map(|irq| irq as u8)
+}
+
+/// Returns `true` if the local APIC delivered `vector` and has not seen
+/// its EOI yet.  Interrupts from the 8259, spurious ones included, never
+/// set this.
+///
+/// # Safety
+/// [`init`] must have succeeded.
+pub unsafe fn is_in_service(vector: u8) -> bool {
+    let isr = unsafe { lapic_read(LAPIC_ISR + (vector as u32 / 32) * 0x10) };
+    isr & (1 << (vector % 32)) != 0
+}
+
+/// Signal End-Of-Interrupt to the local APIC.  Unlike the PIC the APIC
+/// does not care which IRQ is being acknowledged.
+///
+/// # Safety
+/// [`init`] must have succeeded.
+pub unsafe fn send_eoi(_irq: u8) {
+    unsafe { lapic_write(LAPIC_EOI, 0); }
+}
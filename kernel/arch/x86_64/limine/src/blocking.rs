@@ -0,0 +1,440 @@
+//! Sleeping synchronization primitives.
+//!
+//! Unlike [`crate::sync::IrqSafeMutex`], these put a thread that has to
+//! wait to sleep on a [`WaitQueue`] instead of spinning with interrupts
+//! off, so they can be held across slow operations such as I/O.  For the
+//! same reason they must not be used from interrupt handlers.
+//!
+//! Each blocking operation has a `try_` variant that fails instead of
+//! waiting and a `_timeout` variant that gives up after a number of
+//! milliseconds.  [`Condvar`] has only the latter: there is nothing to try.
+//! Timeouts are timed by the PIT tick; without one, a `_timeout` variant
+//! that has to sleep panics instead of sleeping forever.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, ThreadId};
+use crate::wait::WaitQueue;
+
+// ── Mutex ───────────────────────────────────────────────────────────
+
+struct MutexState {
+    locked: bool,
+    /// The holder; `None` if unlocked or taken before [`thread::init`].
+    owner: Option<ThreadId>,
+}
+
+/// A mutual-exclusion lock that sleeps while it is taken, and records the
+/// thread holding it.
+pub struct Mutex<T> {
+    state: IrqSafeMutex<MutexState>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// RAII guard for [`Mutex`].  Dropping it unlocks the mutex and wakes one
+/// waiter.  Must be dropped by the thread that took it.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> Mutex<T> {
+    /// Create a new, unlocked mutex.
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqSafeMutex::new(MutexState { locked: false, owner: None }),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.locked {
+            return false;
+        }
+        state.locked = true;
+        state.owner = thread::current_id();
+        true
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        MutexGuard { mutex: self, _not_send: PhantomData }
+    }
+
+    /// Sleep until the lock is free, then take it.
+    ///
+    /// # Panics
+    /// If the calling thread already holds the lock.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.check_recursion();
+        self.waiters.wait_event(|| self.acquire());
+        self.guard()
+    }
+
+    /// Take the lock if it is free.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire().then(|| self.guard())
+    }
+
+    /// Like [`lock`](Self::lock), but give up after `timeout_ms`
+    /// milliseconds.
+    pub fn lock_timeout(&self, timeout_ms: u64) -> Option<MutexGuard<'_, T>> {
+        self.check_recursion();
+        self.waiters.wait_event_timeout(|| self.acquire(), timeout_ms).then(|| self.guard())
+    }
+
+    fn check_recursion(&self) {
+        let owner = self.owner();
+        if owner.is_some() && owner == thread::current_id() {
+            panic!("blocking::Mutex: thread already holds the lock");
+        }
+    }
+
+    /// The thread holding the lock, if it is taken by a thread.
+    pub fn owner(&self) -> Option<ThreadId> {
+        self.state.lock().owner
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.state.lock().locked
+    }
+
+    /// Mutable access without locking; the borrow proves exclusivity.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard proves the lock is held.
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard proves the lock is held.
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        *self.mutex.state.lock() = MutexState { locked: false, owner: None };
+        self.mutex.waiters.wake_one();
+    }
+}
+
+// ── Semaphore ───────────────────────────────────────────────────────
+
+/// A counting semaphore.
+pub struct Semaphore {
+    count: IrqSafeMutex<usize>,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    /// Create a semaphore with `count` permits.
+    pub const fn new(count: usize) -> Self {
+        Self { count: IrqSafeMutex::new(count), waiters: WaitQueue::new() }
+    }
+
+    fn take(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    /// Sleep until a permit is available, then take it.
+    pub fn acquire(&self) {
+        self.waiters.wait_event(|| self.take());
+    }
+
+    /// Take a permit if one is available.
+    pub fn try_acquire(&self) -> bool {
+        self.take()
+    }
+
+    /// Like [`acquire`](Self::acquire), but give up after `timeout_ms`
+    /// milliseconds.  Returns `false` if it timed out.
+    pub fn acquire_timeout(&self, timeout_ms: u64) -> bool {
+        self.waiters.wait_event_timeout(|| self.take(), timeout_ms)
+    }
+
+    /// Return a permit, waking one waiter.
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.waiters.wake_one();
+    }
+
+    /// Permits currently available.
+    pub fn available(&self) -> usize {
+        *self.count.lock()
+    }
+}
+
+// ── Condvar ─────────────────────────────────────────────────────────
+
+/// A condition variable, used with [`Mutex`].
+///
+/// As usual, wake-ups can be spurious: wait in a loop that re-checks the
+/// condition.
+pub struct Condvar {
+    /// Bumped by every notification; a waiter sleeps until it changes.
+    generation: AtomicU64,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self { generation: AtomicU64::new(0), waiters: WaitQueue::new() }
+    }
+
+    /// Unlock `guard`'s mutex, sleep until notified, and lock it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // Read before unlocking, so a notification sent by the next holder
+        // of the mutex is seen.
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters.wait_event(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Like [`wait`](Self::wait), but stop waiting for a notification after
+    /// `timeout_ms` milliseconds.  The mutex is locked again either way;
+    /// the flag is `false` if the wait timed out.
+    pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T>, timeout_ms: u64) -> (MutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        let notified = self
+            .waiters
+            .wait_event_timeout(|| self.generation.load(Ordering::Acquire) != generation, timeout_ms);
+        (mutex.lock(), notified)
+    }
+
+    /// Wake one waiting thread.
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Wake every waiting thread.
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ── RwLock ──────────────────────────────────────────────────────────
+
+struct RwState {
+    readers: usize,
+    writer: bool,
+    /// The writer holding the lock, as for [`Mutex::owner`].
+    owner: Option<ThreadId>,
+    /// Writers waiting for the lock.  New readers wait while there are
+    /// any, so a steady stream of readers cannot starve writers.
+    writers_waiting: usize,
+}
+
+/// A reader-writer lock: any number of readers or one writer, sleeping
+/// while it cannot be taken.  Waiting writers take precedence over new
+/// readers.
+pub struct RwLock<T> {
+    state: IrqSafeMutex<RwState>,
+    readers: WaitQueue,
+    writers: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// RAII guard for shared access to an [`RwLock`].
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+/// RAII guard for exclusive access to an [`RwLock`].
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> RwLock<T> {
+    /// Create a new, unlocked lock.
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqSafeMutex::new(RwState { readers: 0, writer: false, owner: None, writers_waiting: 0 }),
+            readers: WaitQueue::new(),
+            writers: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    fn acquire_read(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer || state.writers_waiting > 0 {
+            return false;
+        }
+        state.readers += 1;
+        true
+    }
+
+    fn acquire_write(&self) -> bool {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return false;
+        }
+        state.writer = true;
+        state.owner = thread::current_id();
+        true
+    }
+
+    fn read_guard(&self) -> RwLockReadGuard<'_, T> {
+        RwLockReadGuard { lock: self, _not_send: PhantomData }
+    }
+
+    fn write_guard(&self) -> RwLockWriteGuard<'_, T> {
+        RwLockWriteGuard { lock: self, _not_send: PhantomData }
+    }
+
+    /// Sleep until no writer holds or waits for the lock, then take it for
+    /// reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.readers.wait_event(|| self.acquire_read());
+        self.read_guard()
+    }
+
+    /// Take the lock for reading if that is possible without waiting.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.acquire_read().then(|| self.read_guard())
+    }
+
+    /// Like [`read`](Self::read), but give up after `timeout_ms`
+    /// milliseconds.
+    pub fn read_timeout(&self, timeout_ms: u64) -> Option<RwLockReadGuard<'_, T>> {
+        self.readers.wait_event_timeout(|| self.acquire_read(), timeout_ms).then(|| self.read_guard())
+    }
+
+    /// Sleep until the lock is free, then take it for writing.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.state.lock().writers_waiting += 1;
+        self.writers.wait_event(|| self.acquire_write());
+        self.stop_waiting_to_write();
+        self.write_guard()
+    }
+
+    /// Take the lock for writing if it is free.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.acquire_write().then(|| self.write_guard())
+    }
+
+    /// Like [`write`](Self::write), but give up after `timeout_ms`
+    /// milliseconds.
+    pub fn write_timeout(&self, timeout_ms: u64) -> Option<RwLockWriteGuard<'_, T>> {
+        self.state.lock().writers_waiting += 1;
+        let acquired = self.writers.wait_event_timeout(|| self.acquire_write(), timeout_ms);
+        self.stop_waiting_to_write();
+        acquired.then(|| self.write_guard())
+    }
+
+    /// Drop out of `writers_waiting`.  Readers held back only by this
+    /// writer may go ahead now.
+    fn stop_waiting_to_write(&self) {
+        let mut state = self.state.lock();
+        state.writers_waiting -= 1;
+        let release_readers = state.writers_waiting == 0 && !state.writer;
+        drop(state);
+        if release_readers {
+            self.readers.wake_all();
+        }
+    }
+
+    /// The writer holding the lock, if it is taken for writing by a thread.
+    pub fn owner(&self) -> Option<ThreadId> {
+        self.state.lock().owner
+    }
+
+    /// Mutable access without locking; the borrow proves exclusivity.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard proves no writer holds the lock.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.readers -= 1;
+        let last = state.readers == 0;
+        drop(state);
+        if last {
+            self.lock.writers.wake_one();
+        }
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard proves the lock is held exclusively.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard proves the lock is held exclusively.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        let mut state = self.lock.state.lock();
+        state.writer = false;
+        state.owner = None;
+        let writers_waiting = state.writers_waiting > 0;
+        drop(state);
+        if writers_waiting {
+            self.lock.writers.wake_one();
+        } else {
+            self.lock.readers.wake_all();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Minimal console output for code that lives below the terminal crate.
+//!
+//! `tty-x86_64` depends on this crate, so we cannot call into it directly.
+//! Instead we go through librust's C-ABI `putchar`, which is resolved when
+//! the final kernel image is linked.
+
+unsafe extern "C" {
+    fn putchar(c: u8) -> u8;
+    fn terminal_seize();
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Print a byte slice to the terminal.
+pub fn print(s: &[u8]) {
+    for &byte in s {
+        // SAFETY: `putchar` only takes the terminal lock and draws a glyph.
+        unsafe { putchar(byte); }
+    }
+}
+
+/// Make sure printing cannot hang on the terminal lock, breaking it if it
+/// stays taken.  Only for reporting fatal errors: the holder may be the
+/// code that just crashed, and will never release it.
+pub fn seize() {
+    // SAFETY: `terminal_seize` only inspects and releases the lock.
+    unsafe { terminal_seize() };
+}
+
+/// Print a byte slice followed by a newline.
+pub fn println(s: &[u8]) {
+    print(s);
+    print(b"\n");
+}
+
+/// Print `n` as a zero-padded 16-digit hexadecimal number with a `0x` prefix.
+pub fn print_hex(n: u64) {
+    let mut buf = [0u8; 18];
+    buf[0] = b'0';
+    buf[1] = b'x';
+    for i in 0..16 {
+        buf[17 - i] = HEX_DIGITS[((n >> (i * 4)) & 0xF) as usize];
+    }
+    print(&buf);
+}
+
+/// Print `n` in decimal.
+pub fn print_dec(n: u64) {
+    let mut buf = [0u8; 20];
+    let mut len = 0;
+    let mut val = n;
+    loop {
+        buf[buf.len() - 1 - len] = b'0' + (val % 10) as u8;
+        len += 1;
+        val /= 10;
+        if val == 0 {
+            break;
+        }
+    }
+    print(&buf[buf.len() - len..]);
+}
@@ -0,0 +1,338 @@
+//! CPU identification and extended-state enabling.
+//!
+//! [`init`] reads the CPUID leaves the kernel cares about once, on the BSP,
+//! into a [`CpuInfo`] that can be queried with [`has`].  It also turns on
+//! XSAVE and sets XCR0 to every state component the kernel knows how to
+//! manage (x87, SSE, AVX and AVX-512), so AVX code no longer raises #UD.
+//! Each AP repeats the per-CPU part through [`init_ap`].
+
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const CPUID_VENDOR: u32 = 0x0;
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_EXTENDED_FEATURES: u32 = 0x7;
+const CPUID_XSAVE: u32 = 0xD;
+const CPUID_MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+const CPUID_EXTENDED_INFO: u32 = 0x8000_0001;
+const CPUID_BRAND_STRING: u32 = 0x8000_0002;
+const CPUID_ADVANCED_POWER: u32 = 0x8000_0007;
+
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+// XCR0 state components.
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+const XCR0_OPMASK: u64 = 1 << 5;
+const XCR0_ZMM_HI256: u64 = 1 << 6;
+const XCR0_HI16_ZMM: u64 = 1 << 7;
+/// AVX-512 state; the three components can only be enabled together.
+const XCR0_AVX512: u64 = XCR0_OPMASK | XCR0_ZMM_HI256 | XCR0_HI16_ZMM;
+
+#[derive(Clone, Copy)]
+enum Reg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+macro_rules! features {
+    ($($(#[$doc:meta])* $name:ident = $text:literal, $leaf:expr, $subleaf:expr, $reg:ident, $bit:expr;)*) => {
+        /// CPU features the kernel can query with [`has`].
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub enum Feature {
+            $($(#[$doc])* $name,)*
+        }
+
+        impl Feature {
+            /// Every feature, in declaration order.
+            pub const ALL: &[Feature] = &[$(Feature::$name,)*];
+
+            /// Lower-case name, as used by `/proc/cpuinfo`.
+            pub const fn name(self) -> &'static [u8] {
+                match self {
+                    $(Feature::$name => $text,)*
+                }
+            }
+
+            /// CPUID leaf, subleaf, register and bit reporting the feature.
+            const fn location(self) -> (u32, u32, Reg, u32) {
+                match self {
+                    $(Feature::$name => ($leaf, $subleaf, Reg::$reg, $bit),)*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    Fpu = b"fpu", CPUID_FEATURES, 0, Edx, 0;
+    Tsc = b"tsc", CPUID_FEATURES, 0, Edx, 4;
+    Msr = b"msr", CPUID_FEATURES, 0, Edx, 5;
+    Pae = b"pae", CPUID_FEATURES, 0, Edx, 6;
+    Apic = b"apic", CPUID_FEATURES, 0, Edx, 9;
+    Pge = b"pge", CPUID_FEATURES, 0, Edx, 13;
+    Pat = b"pat", CPUID_FEATURES, 0, Edx, 16;
+    Clflush = b"clflush", CPUID_FEATURES, 0, Edx, 19;
+    Sse = b"sse", CPUID_FEATURES, 0, Edx, 25;
+    Sse2 = b"sse2", CPUID_FEATURES, 0, Edx, 26;
+    Sse3 = b"sse3", CPUID_FEATURES, 0, Ecx, 0;
+    Pclmulqdq = b"pclmulqdq", CPUID_FEATURES, 0, Ecx, 1;
+    Ssse3 = b"ssse3", CPUID_FEATURES, 0, Ecx, 9;
+    Fma = b"fma", CPUID_FEATURES, 0, Ecx, 12;
+    Cx16 = b"cx16", CPUID_FEATURES, 0, Ecx, 13;
+    Sse41 = b"sse4_1", CPUID_FEATURES, 0, Ecx, 19;
+    Sse42 = b"sse4_2", CPUID_FEATURES, 0, Ecx, 20;
+    X2apic = b"x2apic", CPUID_FEATURES, 0, Ecx, 21;
+    Movbe = b"movbe", CPUID_FEATURES, 0, Ecx, 22;
+    Popcnt = b"popcnt", CPUID_FEATURES, 0, Ecx, 23;
+    TscDeadline = b"tsc_deadline_timer", CPUID_FEATURES, 0, Ecx, 24;
+    Aes = b"aes", CPUID_FEATURES, 0, Ecx, 25;
+    Xsave = b"xsave", CPUID_FEATURES, 0, Ecx, 26;
+    /// XSAVE enabled by the OS (CR4.OSXSAVE); set once [`init`] has run.
+    Osxsave = b"osxsave", CPUID_FEATURES, 0, Ecx, 27;
+    Avx = b"avx", CPUID_FEATURES, 0, Ecx, 28;
+    F16c = b"f16c", CPUID_FEATURES, 0, Ecx, 29;
+    Rdrand = b"rdrand", CPUID_FEATURES, 0, Ecx, 30;
+    /// Running under a hypervisor.
+    Hypervisor = b"hypervisor", CPUID_FEATURES, 0, Ecx, 31;
+    Fsgsbase = b"fsgsbase", CPUID_EXTENDED_FEATURES, 0, Ebx, 0;
+    Bmi1 = b"bmi1", CPUID_EXTENDED_FEATURES, 0, Ebx, 3;
+    Avx2 = b"avx2", CPUID_EXTENDED_FEATURES, 0, Ebx, 5;
+    Smep = b"smep", CPUID_EXTENDED_FEATURES, 0, Ebx, 7;
+    Bmi2 = b"bmi2", CPUID_EXTENDED_FEATURES, 0, Ebx, 8;
+    Erms = b"erms", CPUID_EXTENDED_FEATURES, 0, Ebx, 9;
+    Invpcid = b"invpcid", CPUID_EXTENDED_FEATURES, 0, Ebx, 10;
+    Avx512f = b"avx512f", CPUID_EXTENDED_FEATURES, 0, Ebx, 16;
+    Rdseed = b"rdseed", CPUID_EXTENDED_FEATURES, 0, Ebx, 18;
+    Smap = b"smap", CPUID_EXTENDED_FEATURES, 0, Ebx, 20;
+    Clflushopt = b"clflushopt", CPUID_EXTENDED_FEATURES, 0, Ebx, 23;
+    Umip = b"umip", CPUID_EXTENDED_FEATURES, 0, Ecx, 2;
+    Pku = b"pku", CPUID_EXTENDED_FEATURES, 0, Ecx, 3;
+    Xsaveopt = b"xsaveopt", CPUID_XSAVE, 1, Eax, 0;
+    Xsavec = b"xsavec", CPUID_XSAVE, 1, Eax, 1;
+    Xsaves = b"xsaves", CPUID_XSAVE, 1, Eax, 3;
+    Syscall = b"syscall", CPUID_EXTENDED_INFO, 0, Edx, 11;
+    Nx = b"nx", CPUID_EXTENDED_INFO, 0, Edx, 20;
+    Page1Gb = b"pdpe1gb", CPUID_EXTENDED_INFO, 0, Edx, 26;
+    Rdtscp = b"rdtscp", CPUID_EXTENDED_INFO, 0, Edx, 27;
+    LongMode = b"lm", CPUID_EXTENDED_INFO, 0, Edx, 29;
+    /// The TSC runs at a constant rate in all P/C-states.
+    InvariantTsc = b"constant_tsc", CPUID_ADVANCED_POWER, 0, Edx, 8;
+}
+
+/// Identification and feature data of the boot CPU.
+#[derive(Clone, Copy)]
+pub struct CpuInfo {
+    /// Vendor string, e.g. `GenuineIntel` or `AuthenticAMD`.
+    pub vendor: [u8; 12],
+    /// Processor brand string, NUL-padded; empty if not reported.
+    pub brand: [u8; 48],
+    /// Display family (base plus extended family).
+    pub family: u32,
+    /// Display model (base plus extended model where applicable).
+    pub model: u32,
+    /// Stepping ID.
+    pub stepping: u32,
+    /// Highest basic CPUID leaf.
+    pub max_leaf: u32,
+    /// Highest extended CPUID leaf.
+    pub max_extended_leaf: u32,
+    /// State components enabled in XCR0; zero without XSAVE.
+    pub xcr0: u64,
+    /// Size in bytes of an XSAVE area for the components in `xcr0`.
+    pub xsave_size: u32,
+    /// One bit per [`Feature`], indexed by discriminant.
+    features: u64,
+}
+
+impl CpuInfo {
+    const fn empty() -> Self {
+        Self {
+            vendor: [0; 12],
+            brand: [0; 48],
+            family: 0,
+            model: 0,
+            stepping: 0,
+            max_leaf: 0,
+            max_extended_leaf: 0,
+            xcr0: 0,
+            xsave_size: 0,
+            features: 0,
+        }
+    }
+
+    /// Returns `true` if the CPU reports `feature`.
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u32) != 0
+    }
+
+    /// The brand string without its padding.
+    pub fn brand(&self) -> &[u8] {
+        let end = self.brand.iter().position(|&b| b == 0).unwrap_or(self.brand.len());
+        self.brand[..end].trim_ascii()
+    }
+}
+
+/// CPU info, wrapped in UnsafeCell so we can fill it in once during boot.
+struct CpuInfoCell(UnsafeCell<CpuInfo>);
+unsafe impl Sync for CpuInfoCell {}
+
+static INFO: CpuInfoCell = CpuInfoCell(UnsafeCell::new(CpuInfo::empty()));
+static INITIALISED: AtomicBool = AtomicBool::new(false);
+
+/// Whether `leaf` exists on a CPU with the given maximum leaves.
+fn leaf_supported(leaf: u32, max_leaf: u32, max_extended_leaf: u32) -> bool {
+    if leaf >= CPUID_MAX_EXTENDED_LEAF { leaf <= max_extended_leaf } else { leaf <= max_leaf }
+}
+
+/// Read every [`Feature`] bit of the calling CPU.
+fn read_features(max_leaf: u32, max_extended_leaf: u32) -> u64 {
+    let mut features = 0;
+    for &feature in Feature::ALL {
+        let (leaf, subleaf, reg, bit) = feature.location();
+        if !leaf_supported(leaf, max_leaf, max_extended_leaf) {
+            continue;
+        }
+        let result = __cpuid_count(leaf, subleaf);
+        let value = match reg {
+            Reg::Eax => result.eax,
+            Reg::Ebx => result.ebx,
+            Reg::Ecx => result.ecx,
+            Reg::Edx => result.edx,
+        };
+        if value & (1 << bit) != 0 {
+            features |= 1 << feature as u32;
+        }
+    }
+    features
+}
+
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    unsafe {
+        core::arch::asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+unsafe fn xsetbv(xcr: u32, value: u64) {
+    unsafe {
+        core::arch::asm!(
+            "xsetbv",
+            in("ecx") xcr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+}
+
+/// The XCR0 value the kernel runs with: x87 and SSE always, plus AVX and
+/// AVX-512 where the CPU supports the whole set.
+fn wanted_xcr0(info: &CpuInfo) -> u64 {
+    let leaf = __cpuid_count(CPUID_XSAVE, 0);
+    let supported = ((leaf.edx as u64) << 32) | leaf.eax as u64;
+
+    let mut xcr0 = XCR0_X87 | XCR0_SSE;
+    if info.has(Feature::Avx) && supported & XCR0_AVX != 0 {
+        xcr0 |= XCR0_AVX;
+        if info.has(Feature::Avx512f) && supported & XCR0_AVX512 == XCR0_AVX512 {
+            xcr0 |= XCR0_AVX512;
+        }
+    }
+    xcr0
+}
+
+/// Enable XSAVE and program XCR0 on the calling CPU.
+unsafe fn enable_extended_state(xcr0: u64) {
+    unsafe {
+        write_cr4(read_cr4() | CR4_OSXSAVE);
+        xsetbv(0, xcr0);
+    }
+}
+
+/// Identify the boot CPU and enable its extended state components.
+///
+/// # Safety
+/// Must be called once, on the BSP, before any AP is started.
+pub unsafe fn init() {
+    // SAFETY: single-threaded init context; no other references exist.
+    let info = unsafe { &mut *INFO.0.get() };
+
+    let vendor = __cpuid(CPUID_VENDOR);
+    info.max_leaf = vendor.eax;
+    for (i, reg) in [vendor.ebx, vendor.edx, vendor.ecx].iter().enumerate() {
+        info.vendor[i * 4..i * 4 + 4].copy_from_slice(&reg.to_le_bytes());
+    }
+
+    let signature = __cpuid(CPUID_FEATURES).eax;
+    let base_family = (signature >> 8) & 0xF;
+    let base_model = (signature >> 4) & 0xF;
+    info.stepping = signature & 0xF;
+    info.family = if base_family == 0xF { base_family + ((signature >> 20) & 0xFF) } else { base_family };
+    info.model = if base_family == 0x6 || base_family == 0xF {
+        base_model | (((signature >> 16) & 0xF) << 4)
+    } else {
+        base_model
+    };
+
+    info.max_extended_leaf = __cpuid(CPUID_MAX_EXTENDED_LEAF).eax;
+    if info.max_extended_leaf >= CPUID_BRAND_STRING + 2 {
+        for i in 0..3 {
+            let leaf = __cpuid(CPUID_BRAND_STRING + i);
+            for (j, reg) in [leaf.eax, leaf.ebx, leaf.ecx, leaf.edx].iter().enumerate() {
+                let offset = i as usize * 16 + j * 4;
+                info.brand[offset..offset + 4].copy_from_slice(&reg.to_le_bytes());
+            }
+        }
+    }
+
+    info.features = read_features(info.max_leaf, info.max_extended_leaf);
+    if info.has(Feature::Xsave) {
+        info.xcr0 = wanted_xcr0(info);
+        unsafe { enable_extended_state(info.xcr0); }
+        // EBX reflects the components now enabled in XCR0, and CPUID now
+        // reports OSXSAVE.
+        info.xsave_size = __cpuid_count(CPUID_XSAVE, 0).ebx;
+        info.features = read_features(info.max_leaf, info.max_extended_leaf);
+    }
+
+    INITIALISED.store(true, Ordering::Release);
+}
+
+/// Enable the same extended state on an AP as on the BSP.
+///
+/// # Safety
+/// [`init`] must have run on the BSP; call once per AP.
+pub unsafe fn init_ap() {
+    let xcr0 = info().xcr0;
+    if xcr0 != 0 {
+        unsafe { enable_extended_state(xcr0); }
+    }
+}
+
+/// The boot CPU's identification and features.
+pub fn info() -> &'static CpuInfo {
+    static EMPTY: CpuInfo = CpuInfo::empty();
+    if INITIALISED.load(Ordering::Acquire) {
+        // SAFETY: INFO is never written again once INITIALISED is set.
+        unsafe { &*INFO.0.get() }
+    } else {
+        &EMPTY
+    }
+}
+
+/// Returns `true` if the boot CPU reports `feature`.
+pub fn has(feature: Feature) -> bool {
+    info().has(feature)
+}
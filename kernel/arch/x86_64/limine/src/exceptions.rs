@@ -0,0 +1,304 @@
+//! CPU exception handling (vectors 0-31).
+//!
+//! Each vector gets its own assembly stub so the handler knows exactly which
+//! exception fired.  The handler dumps the saved register state to the
+//! terminal and halts, which turns silent hangs and triple faults into a
+//! readable report.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::addr::VirtAddr;
+use crate::console::{self, print, println, print_hex};
+use crate::interrupts::InterruptFrame;
+use crate::paging;
+use crate::stack;
+use crate::uaccess;
+
+// Per-vector entry stubs.  Vectors where the CPU pushes an error code only
+// push their vector number; the rest push a dummy zero first so every frame
+// has the same layout.
+core::arch::global_asm!(
+    ".macro EXC_NOERR num",
+    "_exception_stub_\\num:",
+    "push 0",
+    "push \\num",
+    "jmp _isr_common",
+    ".endm",
+    ".macro EXC_ERR num",
+    "_exception_stub_\\num:",
+    "push \\num",
+    "jmp _isr_common",
+    ".endm",
+    "EXC_NOERR 0",
+    "EXC_NOERR 1",
+    "EXC_NOERR 2",
+    "EXC_NOERR 3",
+    "EXC_NOERR 4",
+    "EXC_NOERR 5",
+    "EXC_NOERR 6",
+    "EXC_NOERR 7",
+    "EXC_ERR   8",
+    "EXC_NOERR 9",
+    "EXC_ERR   10",
+    "EXC_ERR   11",
+    "EXC_ERR   12",
+    "EXC_ERR   13",
+    "EXC_ERR   14",
+    "EXC_NOERR 15",
+    "EXC_NOERR 16",
+    "EXC_ERR   17",
+    "EXC_NOERR 18",
+    "EXC_NOERR 19",
+    "EXC_NOERR 20",
+    "EXC_ERR   21",
+    "EXC_NOERR 22",
+    "EXC_NOERR 23",
+    "EXC_NOERR 24",
+    "EXC_NOERR 25",
+    "EXC_NOERR 26",
+    "EXC_NOERR 27",
+    "EXC_NOERR 28",
+    "EXC_ERR   29",
+    "EXC_ERR   30",
+    "EXC_NOERR 31",
+    // Table of stub addresses, indexed by vector.
+    ".pushsection .rodata",
+    ".balign 8",
+    ".global _exception_stub_table",
+    "_exception_stub_table:",
+    ".irp num, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23,24,25,26,27,28,29,30,31",
+    ".quad _exception_stub_\\num",
+    ".endr",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static _exception_stub_table: [u64; 32];
+}
+
+/// Address of the entry stub for exception `vector` (0-31).
+pub fn stub_address(vector: usize) -> u64 {
+    // SAFETY: the table is immutable and filled in at link time.
+    unsafe { _exception_stub_table[vector] }
+}
+
+/// Vector 8, #DF.
+const DOUBLE_FAULT: u64 = 8;
+/// Vector 14, #PF.
+const PAGE_FAULT: u64 = 14;
+
+/// Set once an exception report has started.
+static REPORTING: AtomicBool = AtomicBool::new(false);
+
+// #PF error code bits.
+const PF_PROTECTION: u64 = 1 << 0;
+const PF_WRITE: u64 = 1 << 1;
+const PF_USER: u64 = 1 << 2;
+const PF_RESERVED: u64 = 1 << 3;
+const PF_INSTRUCTION: u64 = 1 << 4;
+
+/// Mnemonic and description for each exception vector.
+static EXCEPTION_NAMES: [&[u8]; 32] = [
+    b"#DE Divide Error",
+    b"#DB Debug",
+    b"NMI Non-Maskable Interrupt",
+    b"#BP Breakpoint",
+    b"#OF Overflow",
+    b"#BR Bound Range Exceeded",
+    b"#UD Invalid Opcode",
+    b"#NM Device Not Available",
+    b"#DF Double Fault",
+    b"Coprocessor Segment Overrun",
+    b"#TS Invalid TSS",
+    b"#NP Segment Not Present",
+    b"#SS Stack-Segment Fault",
+    b"#GP General Protection Fault",
+    b"#PF Page Fault",
+    b"Reserved",
+    b"#MF x87 Floating-Point Exception",
+    b"#AC Alignment Check",
+    b"#MC Machine Check",
+    b"#XM SIMD Floating-Point Exception",
+    b"#VE Virtualization Exception",
+    b"#CP Control Protection Exception",
+    b"Reserved",
+    b"Reserved",
+    b"Reserved",
+    b"Reserved",
+    b"Reserved",
+    b"Reserved",
+    b"#HV Hypervisor Injection Exception",
+    b"#VC VMM Communication Exception",
+    b"#SX Security Exception",
+    b"Reserved",
+];
+
+fn read_cr2() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr2", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+/// Print `name=value` followed by two spaces.
+fn print_reg(name: &[u8], value: u64) {
+    print(name);
+    print(b"=");
+    print_hex(value);
+    print(b"  ");
+}
+
+/// Dump the interrupted register state.
+fn dump_frame(frame: &InterruptFrame) {
+    print_reg(b"RIP", frame.rip);
+    print_reg(b"CS ", frame.cs);
+    print_reg(b"RFLAGS", frame.rflags);
+    println(b"");
+    print_reg(b"RSP", frame.rsp);
+    print_reg(b"SS ", frame.ss);
+    println(b"");
+
+    print_reg(b"RAX", frame.rax);
+    print_reg(b"RBX", frame.rbx);
+    print_reg(b"RCX", frame.rcx);
+    println(b"");
+    print_reg(b"RDX", frame.rdx);
+    print_reg(b"RSI", frame.rsi);
+    print_reg(b"RDI", frame.rdi);
+    println(b"");
+    print_reg(b"RBP", frame.rbp);
+    print_reg(b"R8 ", frame.r8);
+    print_reg(b"R9 ", frame.r9);
+    println(b"");
+    print_reg(b"R10", frame.r10);
+    print_reg(b"R11", frame.r11);
+    print_reg(b"R12", frame.r12);
+    println(b"");
+    print_reg(b"R13", frame.r13);
+    print_reg(b"R14", frame.r14);
+    print_reg(b"R15", frame.r15);
+    println(b"");
+}
+
+/// Explain a page fault from CR2 and the error code.
+fn report_page_fault(error_code: u64) {
+    let cr2 = read_cr2();
+    print_reg(b"CR2", cr2);
+    print_reg(b"CR3", read_cr3());
+    println(b"");
+
+    let cause: &[u8] = if error_code & PF_PROTECTION != 0 { b"protection violation" } else { b"page not present" };
+    let access: &[u8] = if error_code & PF_WRITE != 0 { b", write" } else { b", read" };
+    let mode: &[u8] = if error_code & PF_USER != 0 { b", user mode" } else { b", kernel mode" };
+    print(cause);
+    print(access);
+    print(mode);
+    if error_code & PF_RESERVED != 0 {
+        print(b", reserved bit set");
+    }
+    if error_code & PF_INSTRUCTION != 0 {
+        print(b", instruction fetch");
+    }
+    println(b"");
+
+    // A not-present kernel fault may be a guard-page hit.
+    if error_code & (PF_PROTECTION | PF_USER) == 0 {
+        report_stack_overflow(cr2);
+    }
+
+    // A kernel-mode protection fault on a write or fetch is the page
+    // permissions at work: code is never writable and data never
+    // executable.
+    let kernel_protection = error_code & (PF_PROTECTION | PF_USER) == PF_PROTECTION;
+    if kernel_protection && uaccess::is_user_address(cr2) {
+        if error_code & PF_INSTRUCTION != 0 {
+            if uaccess::smep_enabled() {
+                println(b"SMEP violation: kernel executed user memory");
+            }
+        } else if uaccess::smap_enabled() {
+            println(b"SMAP violation: kernel accessed user memory outside copy_from_user/copy_to_user");
+        }
+    } else if kernel_protection && error_code & (PF_WRITE | PF_INSTRUCTION) != 0 {
+        print(b"W^X violation: ");
+        if error_code & PF_INSTRUCTION != 0 {
+            print(b"execution of non-executable memory");
+        } else {
+            print(b"write to read-only memory");
+        }
+        if let Some(section) = paging::kernel_section(VirtAddr::new_truncate(cr2)) {
+            print(b" in kernel ");
+            print(section);
+        }
+        println(b"");
+    }
+}
+
+/// Explain a double fault.  Running a kernel stack into its guard page
+/// ends up here, because the #PF cannot push its frame on the exhausted
+/// stack; CR2 then holds the address of that failed push.
+fn report_double_fault() {
+    let cr2 = read_cr2();
+    print_reg(b"CR2", cr2);
+    println(b"");
+    report_stack_overflow(cr2);
+}
+
+/// Name the task whose stack overflowed, if `addr` is in a guard page.
+fn report_stack_overflow(addr: u64) {
+    stack::find_overflow(VirtAddr::new_truncate(addr), |owner| {
+        print(b"kernel stack overflow in task ");
+        println(owner.as_bytes());
+    });
+}
+
+/// Report a CPU exception and halt, unless it is a page fault inside a
+/// user copy, which resumes at the copy's fixup.
+pub fn handle(frame: &mut InterruptFrame) {
+    if frame.vector == PAGE_FAULT && let Some(fixup) = uaccess::fixup(frame.rip) {
+        frame.rip = fixup;
+        return;
+    }
+
+    // An exception raised while reporting another, say in the terminal
+    // itself, would only recurse; so would a second CPU talking over the
+    // first report.
+    if REPORTING.swap(true, Ordering::AcqRel) {
+        halt();
+    }
+    console::seize();
+
+    println(b"");
+    print(b"*** CPU EXCEPTION: ");
+    print(EXCEPTION_NAMES[frame.vector as usize]);
+    print(b" (vector ");
+    print_hex(frame.vector);
+    println(b")");
+
+    print_reg(b"ERROR CODE", frame.error_code);
+    println(b"");
+
+    if frame.vector == PAGE_FAULT {
+        report_page_fault(frame.error_code);
+    } else if frame.vector == DOUBLE_FAULT {
+        report_double_fault();
+    }
+
+    dump_frame(frame);
+    println(b"System halted.");
+    halt();
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("cli; hlt", options(nomem, nostack)); }
+    }
+}
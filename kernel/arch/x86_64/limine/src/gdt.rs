@@ -0,0 +1,180 @@
+//! Kernel-owned GDT and TSS.
+//!
+//! Limine hands over control with its own GDT, which has no TSS and hence no
+//! Interrupt Stack Table.  We install a GDT with flat kernel/user segments
+//! plus a TSS whose IST entries point at dedicated stacks, so that a double
+//! fault caused by a blown kernel stack still has somewhere to run.
+//!
+//! A TSS can only be loaded by one CPU (`ltr` marks its descriptor busy),
+//! so every CPU gets its own GDT, TSS and IST stacks.
+
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+
+use crate::smp::MAX_CPUS;
+
+/// 64-bit kernel code segment.
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+/// Kernel data segment.
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+/// User data segment (RPL 3).  Placed before user code so the layout
+/// matches what `sysret` expects.
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+/// 64-bit user code segment (RPL 3).
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+/// Task State Segment (occupies two GDT slots).
+pub const TSS_SELECTOR: u16 = 0x28;
+
+/// IST slot used by the #DF handler.
+pub const DOUBLE_FAULT_IST: u8 = 1;
+/// IST slot used by the NMI handler.
+pub const NMI_IST: u8 = 2;
+/// IST slot used by the #MC handler.
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+/// Size of each IST stack.
+const IST_STACK_SIZE: usize = 16 * 1024;
+/// Number of IST stacks we allocate (one per IST slot in use).
+const IST_STACK_COUNT: usize = 3;
+
+// Segment descriptors: base 0, limit 0xFFFFF, 4 KiB granularity.
+const KERNEL_CODE: u64 = 0x00AF_9A00_0000_FFFF; // P, DPL 0, code, exec/read, L
+const KERNEL_DATA: u64 = 0x00CF_9200_0000_FFFF; // P, DPL 0, data, read/write
+const USER_DATA: u64 = 0x00CF_F200_0000_FFFF;   // P, DPL 3, data, read/write
+const USER_CODE: u64 = 0x00AF_FA00_0000_FFFF;   // P, DPL 3, code, exec/read, L
+
+/// Present, DPL 0, type 0x9 (available 64-bit TSS).
+const TSS_ACCESS: u64 = 0x89;
+
+// ── TSS ─────────────────────────────────────────────────────────────
+
+#[repr(C, packed(4))]
+struct TaskStateSegment {
+    reserved0: u32,
+    /// Stack pointers loaded on privilege changes to rings 0-2.
+    rsp: [u64; 3],
+    reserved1: u64,
+    /// Interrupt Stack Table; IDT entries refer to these as IST 1-7.
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        Self {
+            reserved0: 0,
+            rsp: [0; 3],
+            reserved1: 0,
+            ist: [0; 7],
+            reserved2: 0,
+            reserved3: 0,
+            // No I/O permission bitmap: point past the end of the segment.
+            iomap_base: size_of::<TaskStateSegment>() as u16,
+        }
+    }
+}
+
+#[repr(C, align(16))]
+struct IstStack([u8; IST_STACK_SIZE]);
+
+#[repr(C, packed)]
+struct GdtPtr {
+    limit: u16,
+    base: u64,
+}
+
+/// One CPU's GDT, TSS and IST stacks.
+struct CpuTables {
+    gdt: [u64; 7],
+    tss: TaskStateSegment,
+    ist_stacks: [IstStack; IST_STACK_COUNT],
+}
+
+impl CpuTables {
+    const fn new() -> Self {
+        Self {
+            gdt: [0; 7],
+            tss: TaskStateSegment::new(),
+            ist_stacks: [const { IstStack([0; IST_STACK_SIZE]) }; IST_STACK_COUNT],
+        }
+    }
+}
+
+/// Per-CPU tables, wrapped in UnsafeCell so each CPU can initialise its own
+/// entry once while coming up.
+struct GdtCell(UnsafeCell<[CpuTables; MAX_CPUS]>);
+unsafe impl Sync for GdtCell {}
+
+static GDT: GdtCell = GdtCell(UnsafeCell::new([const { CpuTables::new() }; MAX_CPUS]));
+
+/// Build the two descriptor slots for a 64-bit TSS at `base`.
+fn tss_descriptor(base: u64, limit: u64) -> [u64; 2] {
+    let low = (limit & 0xFFFF)
+        | ((base & 0xFF_FFFF) << 16)
+        | (TSS_ACCESS << 40)
+        | (((limit >> 16) & 0xF) << 48)
+        | (((base >> 24) & 0xFF) << 56);
+    let high = base >> 32;
+    [low, high]
+}
+
+/// Build the GDT and TSS of CPU `cpu` (0 being the BSP), load them on the
+/// calling CPU, and reload every segment register.
+///
+/// # Safety
+/// Must be called once per CPU, by that CPU, before it loads the IDT (IDT
+/// entries refer to [`KERNEL_CODE_SELECTOR`] and the IST slots).  `cpu`
+/// must be below [`MAX_CPUS`].
+pub unsafe fn init(cpu: usize) {
+    // SAFETY: only CPU `cpu` ever touches this entry.
+    let tables = unsafe { &mut (*GDT.0.get())[cpu] };
+    let CpuTables { gdt, tss, ist_stacks: stacks } = tables;
+
+    // Stacks grow down, so each IST entry points at the top of its stack.
+    for (i, stack) in stacks.iter_mut().enumerate() {
+        tss.ist[i] = stack.0.as_mut_ptr() as u64 + IST_STACK_SIZE as u64;
+    }
+
+    let tss_base = tss as *const TaskStateSegment as u64;
+    let tss_limit = (size_of::<TaskStateSegment>() - 1) as u64;
+    let [tss_low, tss_high] = tss_descriptor(tss_base, tss_limit);
+
+    *gdt = [0, KERNEL_CODE, KERNEL_DATA, USER_DATA, USER_CODE, tss_low, tss_high];
+
+    let gdt_ptr = GdtPtr {
+        limit: (size_of::<[u64; 7]>() - 1) as u16,
+        base: gdt.as_ptr() as u64,
+    };
+
+    // SAFETY: gdt_ptr points to a valid GDT whose code/data selectors match
+    // the constants used below.
+    unsafe {
+        core::arch::asm!(
+            "lgdt [{ptr}]",
+            // Reload CS with a far return to the next instruction.
+            "push {code}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            // Reload the data segment registers.
+            "mov {tmp:e}, {data}",
+            "mov ds, {tmp:x}",
+            "mov es, {tmp:x}",
+            "mov ss, {tmp:x}",
+            "xor {tmp:e}, {tmp:e}",
+            "mov fs, {tmp:x}",
+            "mov gs, {tmp:x}",
+            // Load the task register.
+            "mov {tmp:e}, {tss}",
+            "ltr {tmp:x}",
+            ptr = in(reg) &gdt_ptr,
+            tmp = out(reg) _,
+            code = const KERNEL_CODE_SELECTOR as u64,
+            data = const KERNEL_DATA_SELECTOR as u32,
+            tss = const TSS_SELECTOR as u32,
+        );
+    }
+}
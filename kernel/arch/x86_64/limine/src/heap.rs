@@ -0,0 +1,236 @@
+//! Kernel heap and global allocator.
+//!
+//! The heap is a virtual region of its own, grown on demand by mapping
+//! frames from [`crate::pmm`] through [`crate::paging`].  Free space is a
+//! singly linked list of blocks sorted by address; allocation is first fit
+//! and freed blocks are merged with their neighbours.  Registered as the
+//! `#[global_allocator]`, so every kernel crate can use `alloc`; small
+//! allocations take the [`crate::slab`] fast path instead.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+
+use crate::addr::VirtAddr;
+use crate::console;
+use crate::paging::{kernel_space, PageFlags, PageSize};
+use crate::pmm;
+use crate::slab;
+use crate::sync::IrqSafeMutex;
+
+/// Start of the heap region: PML4 slot 510, between the HHDM and the
+/// kernel image.
+const HEAP_START: u64 = 0xFFFF_FF00_0000_0000;
+/// Upper bound on the heap's size.
+const HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+/// Mapped by [`init`], and the minimum the heap grows by.
+const HEAP_GROW_SIZE: u64 = 256 * 1024;
+
+/// Every block is a multiple of this size and alignment, which also makes
+/// room for a [`FreeBlock`] header in any freed allocation.
+const BLOCK_ALIGN: usize = 16;
+
+/// Heap usage counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    /// Bytes of heap backed by frames.
+    pub mapped_bytes: usize,
+    /// Bytes handed out, after rounding to the block size.
+    pub used_bytes: usize,
+    /// Live allocations.
+    pub allocations: usize,
+}
+
+/// Header of a free block, stored in the block itself.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const _: () = assert!(size_of::<FreeBlock>() <= BLOCK_ALIGN);
+
+struct Heap {
+    /// Free list, sorted by address.
+    head: *mut FreeBlock,
+    /// End of the mapped part of the heap.
+    end: u64,
+    stats: HeapStats,
+}
+
+unsafe impl Send for Heap {}
+
+static HEAP: IrqSafeMutex<Heap> = IrqSafeMutex::new(Heap {
+    head: ptr::null_mut(),
+    end: HEAP_START,
+    stats: HeapStats { mapped_bytes: 0, used_bytes: 0, allocations: 0 },
+});
+
+/// Size and alignment actually reserved for `layout`.
+fn block_layout(layout: Layout) -> (usize, usize) {
+    let size = layout.size().max(1).next_multiple_of(BLOCK_ALIGN);
+    (size, layout.align().max(BLOCK_ALIGN))
+}
+
+impl Heap {
+    /// Carve `size` bytes aligned to `align` out of the first free block
+    /// that can hold them.
+    fn take(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut *mut FreeBlock = &mut self.head;
+        // SAFETY: the free list only links blocks inside the mapped heap,
+        // each large enough for its header.
+        unsafe {
+            while !(*prev).is_null() {
+                let block = *prev;
+                let start = block as usize;
+                let end = start + (*block).size;
+                let aligned = start.next_multiple_of(align);
+
+                if aligned + size <= end {
+                    let next = (*block).next;
+                    // Both leftovers are multiples of BLOCK_ALIGN, so
+                    // either empty or big enough for a header.
+                    let tail = aligned + size;
+                    let mut link = next;
+                    if tail < end {
+                        let rest = tail as *mut FreeBlock;
+                        rest.write(FreeBlock { size: end - tail, next });
+                        link = rest;
+                    }
+                    if aligned > start {
+                        (*block).size = aligned - start;
+                        (*block).next = link;
+                    } else {
+                        *prev = link;
+                    }
+                    return aligned as *mut u8;
+                }
+                prev = &mut (*block).next;
+            }
+        }
+        ptr::null_mut()
+    }
+
+    /// Return `size` bytes at `addr` to the free list, merging them with
+    /// adjacent free blocks.
+    fn give(&mut self, addr: usize, size: usize) {
+        // SAFETY: as in `take`; `addr..addr + size` is heap memory no one
+        // else uses.
+        unsafe {
+            let mut prev: *mut FreeBlock = ptr::null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < addr {
+                prev = next;
+                next = (*next).next;
+            }
+
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            if !next.is_null() && addr + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+
+            if prev.is_null() {
+                self.head = block;
+            } else if prev as usize + (*prev).size == addr {
+                (*prev).size += (*block).size;
+                (*prev).next = (*block).next;
+            } else {
+                (*prev).next = block;
+            }
+        }
+    }
+
+    /// Map at least `bytes` more of the heap and add it to the free list.
+    fn grow(&mut self, bytes: u64) -> bool {
+        let bytes = bytes.max(HEAP_GROW_SIZE).next_multiple_of(PageSize::Size4K.bytes());
+        if self.end + bytes > HEAP_START + HEAP_MAX_SIZE {
+            return false;
+        }
+
+        let start = self.end;
+        let mut space = kernel_space();
+        while self.end < start + bytes {
+            let Some(frame) = pmm::alloc_frame() else { break };
+            if space.map(VirtAddr::new(self.end), frame, PageSize::Size4K, PageFlags::KERNEL_DATA).is_err() {
+                pmm::free_frame(frame);
+                break;
+            }
+            self.end += PageSize::Size4K.bytes();
+        }
+        drop(space);
+
+        let grown = (self.end - start) as usize;
+        if grown == 0 {
+            return false;
+        }
+        self.stats.mapped_bytes += grown;
+        self.give(start as usize, grown);
+        true
+    }
+}
+
+/// Map the initial heap.
+///
+/// Returns `false` if no memory could be mapped; allocations will keep
+/// trying to grow the heap.
+///
+/// # Safety
+/// Must be called once, after [`crate::paging::init`].
+pub unsafe fn init() -> bool {
+    HEAP.lock().grow(HEAP_GROW_SIZE)
+}
+
+/// Current heap usage.
+pub fn stats() -> HeapStats {
+    HEAP.lock().stats
+}
+
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = slab::alloc(layout) {
+            return ptr;
+        }
+
+        let (size, align) = block_layout(layout);
+        let mut heap = HEAP.lock();
+        let mut ptr = heap.take(size, align);
+        // Worst case the new space starts right after a misaligned free
+        // block, so ask for the alignment on top.
+        if ptr.is_null() && heap.grow((size + align) as u64) {
+            ptr = heap.take(size, align);
+        }
+        if !ptr.is_null() {
+            heap.stats.used_bytes += size;
+            heap.stats.allocations += 1;
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: `slab::alloc` served `ptr` if it takes this layout.
+        if unsafe { slab::free(ptr, layout) } {
+            return;
+        }
+
+        let (size, _) = block_layout(layout);
+        let mut heap = HEAP.lock();
+        heap.stats.used_bytes -= size;
+        heap.stats.allocations -= 1;
+        heap.give(ptr as usize, size);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    console::print(b"heap: failed to allocate ");
+    console::print_dec(layout.size() as u64);
+    console::print(b" bytes aligned to ");
+    console::print_dec(layout.align() as u64);
+    console::println(b"");
+    panic!("out of memory");
+}
@@ -0,0 +1,279 @@
+//! High Precision Event Timer driver.
+//!
+//! The HPET is found through its ACPI table.  [`init`] maps the register
+//! block and starts the main counter, which serves as a fixed-rate
+//! high-resolution clock ([`counter`], [`frequency_hz`]).  Each comparator
+//! can raise a one-shot or periodic interrupt through the I/O APIC, as an
+//! alternative timer source to the PIT.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+use crate::acpi;
+use crate::addr::PhysAddr;
+use crate::apic;
+use crate::irq::{self, IrqError, IrqHandler, IRQ_COUNT, ISA_IRQ_COUNT};
+use crate::mmio;
+
+/// Offset of the register block address (inside the Generic Address
+/// Structure at offset 40) in the ACPI HPET table.
+const TABLE_ADDRESS_OFFSET: usize = 44;
+const TABLE_MIN_LENGTH: usize = 56;
+
+/// Size of the register block.
+const REGISTER_BLOCK_SIZE: u64 = 0x400;
+
+// General registers.
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIG: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0F0;
+
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+
+/// The specification caps the counter period at 100 ns.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+// Per-comparator registers.
+const fn timer_config(timer: u8) -> u64 {
+    0x100 + 0x20 * timer as u64
+}
+const fn timer_comparator(timer: u8) -> u64 {
+    0x108 + 0x20 * timer as u64
+}
+
+const TIMER_LEVEL: u64 = 1 << 1;
+const TIMER_ENABLE: u64 = 1 << 2;
+const TIMER_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// Lets the next comparator write set the periodic accumulator.
+const TIMER_VALUE_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1F << TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+
+const MAX_TIMERS: usize = 32;
+/// Marks a comparator without an IRQ in [`TIMER_IRQ`].
+const NO_IRQ: u8 = u8::MAX;
+
+/// Errors returned by the comparator API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HpetError {
+    /// [`init`] found no usable HPET.
+    NotPresent,
+    /// The comparator number is out of range.
+    InvalidTimer,
+    /// The comparator cannot run in periodic mode.
+    PeriodicUnsupported,
+    /// None of the I/O APIC inputs the comparator can drive is free (or
+    /// the I/O APIC is not in use).
+    NoRoute,
+}
+
+/// Virtual address of the register block; zero until [`init`] succeeds.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Counter period in femtoseconds.
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+static TIMER_COUNT: AtomicU8 = AtomicU8::new(0);
+static COUNTER_64BIT: AtomicBool = AtomicBool::new(false);
+/// IRQ each comparator is currently delivering on.
+static TIMER_IRQ: [AtomicU8; MAX_TIMERS] = [const { AtomicU8::new(NO_IRQ) }; MAX_TIMERS];
+
+unsafe fn read(reg: u64) -> u64 {
+    unsafe { core::ptr::read_volatile((BASE.load(Ordering::Acquire) + reg) as *const u64) }
+}
+
+unsafe fn write(reg: u64, value: u64) {
+    unsafe { core::ptr::write_volatile((BASE.load(Ordering::Acquire) + reg) as *mut u64, value) }
+}
+
+/// Locate the HPET via ACPI and start its main counter.
+///
+/// Returns `false` if there is no HPET (or it reports a bogus period).
+///
+/// # Safety
+/// Must be called once, during single-threaded boot.
+pub unsafe fn init() -> bool {
+    let Some(table) = acpi::find_table(b"HPET") else { return false };
+    if table.length < TABLE_MIN_LENGTH {
+        return false;
+    }
+
+    unsafe {
+        let phys = PhysAddr::new(table.read::<u64>(TABLE_ADDRESS_OFFSET));
+        let Some(virt) = mmio::map_uncached(phys, REGISTER_BLOCK_SIZE) else { return false };
+        BASE.store(virt.as_u64(), Ordering::Release);
+
+        let caps = read(GENERAL_CAPABILITIES);
+        let period = caps >> 32;
+        if period == 0 || period > MAX_PERIOD_FS {
+            BASE.store(0, Ordering::Release);
+            return false;
+        }
+        PERIOD_FS.store(period, Ordering::Relaxed);
+        COUNTER_64BIT.store(caps & CAP_COUNTER_64BIT != 0, Ordering::Relaxed);
+        let count = (((caps >> 8) & 0x1F) + 1) as u8;
+        TIMER_COUNT.store(count, Ordering::Relaxed);
+
+        // Halt the counter, and leave IRQ0/IRQ8 to the PIT and RTC.
+        let config = read(GENERAL_CONFIG) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTE);
+        write(GENERAL_CONFIG, config);
+
+        // Quiesce every comparator before the counter starts.
+        for timer in 0..count {
+            let conf = read(timer_config(timer)) & !(TIMER_ENABLE | TIMER_FSB_ENABLE);
+            write(timer_config(timer), conf);
+        }
+
+        write(MAIN_COUNTER, 0);
+        write(GENERAL_CONFIG, config | CONFIG_ENABLE);
+    }
+    true
+}
+
+/// Returns `true` once [`init`] has found and started the HPET.
+pub fn is_available() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// Counter frequency in Hz, or zero without an HPET.
+pub fn frequency_hz() -> u64 {
+    FEMTOSECONDS_PER_SECOND.checked_div(PERIOD_FS.load(Ordering::Relaxed)).unwrap_or(0)
+}
+
+/// Number of comparators, or zero without an HPET.
+pub fn timer_count() -> u8 {
+    TIMER_COUNT.load(Ordering::Relaxed)
+}
+
+/// Current value of the main counter.
+///
+/// The counter is 64 bits wide on most HPETs; a 32-bit counter wraps
+/// after 2^32 ticks, which [`elapsed`] accounts for.
+pub fn counter() -> u64 {
+    if !is_available() {
+        return 0;
+    }
+    // SAFETY: the register block is mapped once `init` has succeeded.
+    unsafe { read(MAIN_COUNTER) }
+}
+
+/// Counter ticks elapsed since the [`counter`] reading `since`.
+pub fn elapsed(since: u64) -> u64 {
+    let now = counter();
+    if COUNTER_64BIT.load(Ordering::Relaxed) {
+        now.wrapping_sub(since)
+    } else {
+        (now as u32).wrapping_sub(since as u32) as u64
+    }
+}
+
+/// Convert nanoseconds to counter ticks (at least one).
+fn ns_to_ticks(ns: u64) -> u64 {
+    let period = PERIOD_FS.load(Ordering::Relaxed) as u128;
+    ((ns as u128 * 1_000_000).div_ceil(period) as u64).max(1)
+}
+
+/// Claim the first free IRQ among the I/O APIC inputs `timer` can drive,
+/// preferring GSIs 16-23 over ISA lines, and install `handler` on it.
+fn claim_route(timer: u8, handler: IrqHandler) -> Result<(u8, u32), HpetError> {
+    // SAFETY: `timer` has been checked against the comparator count.
+    let route_cap = unsafe { read(timer_config(timer)) } >> 32;
+
+    let candidates = (ISA_IRQ_COUNT as u32..IRQ_COUNT as u32).chain(0..ISA_IRQ_COUNT as u32);
+    for gsi in candidates {
+        if route_cap & (1 << gsi) == 0 {
+            continue;
+        }
+        let Some(irq) = apic::irq_for_gsi(gsi) else { continue };
+        match irq::register_irq_handler(irq, handler) {
+            Ok(()) => return Ok((irq, gsi)),
+            Err(IrqError::AlreadyRegistered) => continue,
+            Err(_) => break,
+        }
+    }
+    Err(HpetError::NoRoute)
+}
+
+/// Route `timer` to a free IRQ, install `handler` and arm the comparator
+/// to fire `ticks` from now (and every `ticks` after that if `periodic`).
+fn arm(timer: u8, handler: IrqHandler, ticks: u64, periodic: bool) -> Result<(), HpetError> {
+    if !is_available() {
+        return Err(HpetError::NotPresent);
+    }
+    if timer >= timer_count() {
+        return Err(HpetError::InvalidTimer);
+    }
+    // SAFETY: `timer` is in range.
+    let conf = unsafe { read(timer_config(timer)) };
+    if periodic && conf & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::PeriodicUnsupported);
+    }
+
+    let _ = stop(timer);
+    let (irq, gsi) = claim_route(timer, handler)?;
+    TIMER_IRQ[timer as usize].store(irq, Ordering::Release);
+
+    // Edge-triggered, so the handler has no status bit to clear.
+    let mut conf = conf
+        & !(TIMER_LEVEL | TIMER_PERIODIC | TIMER_32BIT_MODE | TIMER_ROUTE_MASK | TIMER_FSB_ENABLE);
+    conf |= ((gsi as u64) << TIMER_ROUTE_SHIFT) | TIMER_ENABLE;
+
+    // SAFETY: `timer` is in range and its IRQ handler is installed.
+    unsafe {
+        if periodic {
+            // Halt the main counter while the comparator and accumulator
+            // are loaded, so the first period is not already missed.
+            let general = read(GENERAL_CONFIG);
+            write(GENERAL_CONFIG, general & !CONFIG_ENABLE);
+            write(timer_config(timer), conf | TIMER_PERIODIC | TIMER_VALUE_SET);
+            write(timer_comparator(timer), read(MAIN_COUNTER) + ticks);
+            write(timer_comparator(timer), ticks);
+            write(GENERAL_CONFIG, general);
+        } else {
+            write(timer_config(timer), conf);
+            write(timer_comparator(timer), read(MAIN_COUNTER) + ticks);
+        }
+    }
+    Ok(())
+}
+
+/// Call `handler` once, `delay_ns` nanoseconds from now, from comparator
+/// `timer`.  Replaces whatever `timer` was doing before.
+///
+/// `handler` runs in interrupt context, like any [`IrqHandler`].
+pub fn start_oneshot(timer: u8, delay_ns: u64, handler: IrqHandler) -> Result<(), HpetError> {
+    arm(timer, handler, ns_to_ticks(delay_ns), false)
+}
+
+/// Call `handler` `hz` times per second from comparator `timer`.
+/// Replaces whatever `timer` was doing before.
+///
+/// `handler` runs in interrupt context, like any [`IrqHandler`].
+pub fn start_periodic(timer: u8, hz: u32, handler: IrqHandler) -> Result<(), HpetError> {
+    arm(timer, handler, ns_to_ticks(1_000_000_000 / hz.max(1) as u64), true)
+}
+
+/// Disarm comparator `timer` and release its IRQ.
+pub fn stop(timer: u8) -> Result<(), HpetError> {
+    if !is_available() {
+        return Err(HpetError::NotPresent);
+    }
+    if timer >= timer_count() {
+        return Err(HpetError::InvalidTimer);
+    }
+
+    // SAFETY: `timer` is in range.
+    unsafe {
+        let conf = read(timer_config(timer));
+        write(timer_config(timer), conf & !TIMER_ENABLE);
+    }
+
+    let irq = TIMER_IRQ[timer as usize].swap(NO_IRQ, Ordering::AcqRel);
+    if irq != NO_IRQ {
+        let _ = irq::unregister_irq_handler(irq);
+    }
+    Ok(())
+}
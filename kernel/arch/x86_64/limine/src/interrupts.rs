@@ -0,0 +1,245 @@
+//! Common interrupt entry path.
+//!
+//! Every vector stub pushes an error code (the CPU's, or a dummy zero) and
+//! its vector number, then jumps to `_isr_common`.  That routine saves all
+//! general-purpose registers so the stack holds a complete
+//! [`InterruptFrame`], hands a pointer to it to [`interrupt_dispatch`], and
+//! restores everything on the way back out.  The handlers are ordinary
+//! Rust code free to use SSE and AVX registers, so the interrupted code's
+//! extended state is saved below the frame as well, with `xsave` once
+//! [`init_extended_state`] has run and `fxsave` before.
+//!
+//! Interrupts taken from ring 3 `swapgs` on the way in and out so that GS
+//! always points at the [`percpu`](crate::percpu) area inside the kernel,
+//! which also tracks the interrupt nesting depth.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{apic, cpu, exceptions, irq, percpu, smp, thread, uaccess};
+
+/// Size of an `fxsave` area.
+const FXSAVE_SIZE: u64 = 512;
+/// Offset of the XSAVE header, and of its fields after XSTATE_BV.
+const XSAVE_HEADER_OFFSET: u64 = 512;
+
+/// Size of the XSAVE area `_isr_common` saves extended state into, or zero
+/// to use `fxsave`.
+static XSAVE_SIZE: AtomicU64 = AtomicU64::new(0);
+
+/// Register state saved on the stack by `_isr_common` and the CPU.
+///
+/// The field order mirrors the stack layout, lowest address first.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rax: u64,
+    /// Pushed by the per-vector stub.
+    pub vector: u64,
+    /// Pushed by the CPU for some exceptions, otherwise a dummy zero.
+    pub error_code: u64,
+    // Pushed by the CPU on every interrupt.
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// Shared tail of every vector stub.  On entry the stack holds the CPU frame,
+// the error code and the vector number, which leaves RSP 8 mod 16; pushing
+// 15 registers realigns it to 16 for the `call`.  The extended-state area
+// below the frame is aligned further.  RBP keeps the frame address and RBX
+// the XSAVE area size (zero for `fxsave`) across the call, since the mode
+// must not change between save and restore.
+core::arch::global_asm!(
+    ".global _isr_common",
+    "_isr_common:",
+    // The CPU leaves RFLAGS.AC alone, so an interrupt during a user copy
+    // would run its handler, or sit preempted, with SMAP lifted.  `clac`
+    // itself raises #UD without SMAP.
+    "cmp byte ptr [rip + {smap_enabled}], 0",
+    "je 1f",
+    "clac",
+    "1:",
+    // Switch to the kernel GS base if we came from user mode (CS.RPL != 0).
+    "test qword ptr [rsp + 24], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    "inc qword ptr gs:[{irq_depth}]",
+    // Save all general-purpose registers
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rbx",
+    "push rbp",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rbp, rsp",
+    "mov rbx, [rip + {xsave_size}]",
+    "test rbx, rbx",
+    "jz 3f",
+    "sub rsp, rbx",
+    "and rsp, -64",
+    // `xsave` only writes XSTATE_BV of the header, but `xrstor` faults
+    // unless the rest of it is zero.
+    "xor eax, eax",
+    ".irp offset, 8,16,24,32,40,48,56",
+    "mov [rsp + {xsave_header} + \\offset], rax",
+    ".endr",
+    "mov eax, -1",
+    "mov edx, -1",
+    "xsave64 [rsp]",
+    "jmp 4f",
+    "3:",
+    "sub rsp, {fxsave_size}",
+    "and rsp, -16",
+    "fxsave64 [rsp]",
+    "4:",
+    // Pass a pointer to the saved frame to the Rust dispatcher
+    "mov rdi, rbp",
+    "cld",
+    "call interrupt_dispatch",
+    "test rbx, rbx",
+    "jz 3f",
+    "mov eax, -1",
+    "mov edx, -1",
+    "xrstor64 [rsp]",
+    "jmp 4f",
+    "3:",
+    "fxrstor64 [rsp]",
+    "4:",
+    "mov rsp, rbp",
+    // Restore all general-purpose registers
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rbp",
+    "pop rbx",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    "dec qword ptr gs:[{irq_depth}]",
+    // Restore the user GS base if we are returning to user mode.
+    "test qword ptr [rsp + 24], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    // Drop the vector number and error code
+    "add rsp, 16",
+    "iretq",
+    irq_depth = const percpu::IRQ_DEPTH_OFFSET,
+    xsave_size = sym XSAVE_SIZE,
+    smap_enabled = sym uaccess::SMAP_ENABLED,
+    xsave_header = const XSAVE_HEADER_OFFSET,
+    fxsave_size = const FXSAVE_SIZE,
+);
+
+/// Save extended state on interrupt entry with `xsave`, covering every
+/// component [`cpu::init`] enabled, rather than `fxsave`, which misses the
+/// upper halves of the AVX registers.  Does nothing without XSAVE.
+///
+/// # Safety
+/// Must be called after [`cpu::init`], and every AP must have run
+/// [`cpu::init_ap`] before it takes an interrupt.
+pub unsafe fn init_extended_state() {
+    let info = cpu::info();
+    if info.xcr0 != 0 {
+        XSAVE_SIZE.store(info.xsave_size as u64, Ordering::Relaxed);
+    }
+}
+
+/// RFLAGS.IF, the interrupt-enable flag.
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Returns `true` if maskable interrupts are enabled on this CPU.
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        core::arch::asm!("pushfq", "pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+/// Enable maskable interrupts (`sti`).
+#[inline]
+pub fn enable() {
+    unsafe { core::arch::asm!("sti", options(nomem, nostack)); }
+}
+
+/// Disable maskable interrupts (`cli`).
+#[inline]
+pub fn disable() {
+    unsafe { core::arch::asm!("cli", options(nomem, nostack)); }
+}
+
+/// Run `f` with interrupts disabled, restoring the previous state after.
+#[inline]
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = are_enabled();
+    disable();
+    let result = f();
+    if were_enabled {
+        enable();
+    }
+    result
+}
+
+/// Atomically enable interrupts and halt until the next one arrives.
+/// `sti` only takes effect after the following instruction, so an IRQ
+/// cannot slip in between the two and leave us halted.
+#[inline]
+pub fn enable_and_halt() {
+    unsafe { core::arch::asm!("sti", "hlt", options(nomem, nostack)); }
+}
+
+/// Called by `_isr_common` for every vector routed through it.
+#[unsafe(no_mangle)]
+extern "C" fn interrupt_dispatch(frame: &mut InterruptFrame) {
+    let irq_vectors = irq::IRQ_BASE_VECTOR as u64..irq::IRQ_BASE_VECTOR as u64 + irq::IRQ_COUNT as u64;
+
+    if frame.vector < 32 {
+        exceptions::handle(frame);
+    } else if irq_vectors.contains(&frame.vector) {
+        irq::dispatch(frame);
+        // The EOI is out; the timer may have asked for a thread switch.
+        thread::preempt();
+    } else if frame.vector == apic::TIMER_VECTOR as u64 {
+        apic::handle_timer();
+        thread::preempt();
+    } else if frame.vector == smp::WAKE_VECTOR as u64 {
+        smp::handle_wake();
+        // A thread woken for this CPU from another one takes over here
+        // rather than at the next tick.
+        thread::preempt();
+    }
+}
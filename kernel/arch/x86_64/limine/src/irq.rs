@@ -0,0 +1,162 @@
+//! Hardware IRQ dispatch.
+//!
+//! IRQ 0-15 (ISA) are remapped to vectors 0x20-0x2F; with the I/O APIC,
+//! IRQ 16-23 (GSIs 16-23) follow on 0x30-0x37.  Each vector has a tiny
+//! assembly trampoline that feeds into the common interrupt path; from there
+//! [`dispatch`] looks the IRQ up in a handler table, calls the registered
+//! handler and sends the End-Of-Interrupt.  Drivers only ever deal with
+//! [`register_irq_handler`] / [`unregister_irq_handler`].
+//!
+//! Masking and EOIs go to the I/O APIC when [`apic::init`] succeeded, and
+//! to the 8259 PIC otherwise.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::apic;
+use crate::interrupts::InterruptFrame;
+use crate::pic;
+
+/// First IDT vector used for hardware IRQs.
+pub const IRQ_BASE_VECTOR: u8 = 0x20;
+/// Number of ISA IRQ lines (two cascaded 8259 PICs).
+pub const ISA_IRQ_COUNT: usize = 16;
+/// Number of IRQ lines; those past [`ISA_IRQ_COUNT`] only exist with the
+/// I/O APIC.
+pub const IRQ_COUNT: usize = 24;
+
+/// A driver's IRQ handler.  Runs with interrupts disabled; the EOI is sent
+/// after it returns.
+pub type IrqHandler = fn();
+
+/// Errors returned by the registration API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ number is outside 0-23, or above 15 without the I/O APIC.
+    InvalidIrq,
+    /// Another handler already owns this IRQ.
+    AlreadyRegistered,
+    /// No handler is registered for this IRQ.
+    NotRegistered,
+}
+
+// Per-IRQ trampolines: push a dummy error code and the vector number so the
+// frame matches the exception layout, then enter the common path.
+core::arch::global_asm!(
+    ".macro IRQ_STUB irq",
+    "_irq_stub_\\irq:",
+    "push 0",
+    "push 0x20 + \\irq",
+    "jmp _isr_common",
+    ".endm",
+    ".irp irq, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23",
+    "IRQ_STUB \\irq",
+    ".endr",
+    // Table of stub addresses, indexed by IRQ.
+    ".pushsection .rodata",
+    ".balign 8",
+    ".global _irq_stub_table",
+    "_irq_stub_table:",
+    ".irp irq, 0,1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16,17,18,19,20,21,22,23",
+    ".quad _irq_stub_\\irq",
+    ".endr",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static _irq_stub_table: [u64; IRQ_COUNT];
+}
+
+/// Address of the trampoline for `irq` (0-23).
+pub fn stub_address(irq: usize) -> u64 {
+    // SAFETY: the table is immutable and filled in at link time.
+    unsafe { _irq_stub_table[irq] }
+}
+
+/// Registered handlers, stored as `fn()` addresses; zero means "none".
+static HANDLERS: [AtomicUsize; IRQ_COUNT] = [const { AtomicUsize::new(0) }; IRQ_COUNT];
+
+/// The handler slot for `irq`, if the active controller has that line.
+fn handler_slot(irq: u8) -> Result<&'static AtomicUsize, IrqError> {
+    if irq as usize >= ISA_IRQ_COUNT && !apic::is_enabled() {
+        return Err(IrqError::InvalidIrq);
+    }
+    HANDLERS.get(irq as usize).ok_or(IrqError::InvalidIrq)
+}
+
+/// Unmask `irq` on whichever interrupt controller is active.
+unsafe fn unmask_line(irq: u8) {
+    unsafe {
+        if apic::is_enabled() { apic::unmask_irq(irq) } else { pic::unmask_irq(irq) }
+    }
+}
+
+/// Mask `irq` on whichever interrupt controller is active.
+unsafe fn mask_line(irq: u8) {
+    unsafe {
+        if apic::is_enabled() { apic::mask_irq(irq) } else { pic::mask_irq(irq) }
+    }
+}
+
+/// Acknowledge `irq` on whichever interrupt controller is active.
+unsafe fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if apic::is_enabled() { apic::send_eoi(irq) } else { pic::send_eoi(irq) }
+    }
+}
+
+/// Install `handler` for `irq` and unmask the line.
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    let slot = handler_slot(irq)?;
+    slot.compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| IrqError::AlreadyRegistered)?;
+
+    // SAFETY: the handler is in place before the line can fire.
+    unsafe { unmask_line(irq); }
+    Ok(())
+}
+
+/// Mask `irq` and remove its handler.
+pub fn unregister_irq_handler(irq: u8) -> Result<(), IrqError> {
+    let slot = handler_slot(irq)?;
+
+    // SAFETY: masking a line is always allowed.
+    unsafe { mask_line(irq); }
+
+    if slot.swap(0, Ordering::AcqRel) == 0 {
+        return Err(IrqError::NotRegistered);
+    }
+    Ok(())
+}
+
+/// Called from the common interrupt path for vectors 0x20-0x37.
+pub fn dispatch(frame: &mut InterruptFrame) {
+    let irq = (frame.vector - IRQ_BASE_VECTOR as u64) as u8;
+
+    // SAFETY: we are in interrupt context with IF=0.
+    unsafe {
+        // IRQ 7 and 15 can be raised spuriously by the PICs, even while
+        // they are masked and the APIC is in charge.  A spurious IRQ must
+        // not be acknowledged, except that a spurious IRQ 15 still needs an
+        // EOI on the master for the cascade line.
+        if irq == 7 || irq == 15 {
+            if apic::is_enabled() {
+                if !apic::is_in_service(frame.vector as u8) {
+                    return;
+                }
+            } else if !pic::is_in_service(irq) {
+                if irq == 15 {
+                    pic::send_eoi(2);
+                }
+                return;
+            }
+        }
+
+        let handler = HANDLERS[irq as usize].load(Ordering::Acquire);
+        if handler != 0 {
+            let handler: IrqHandler = core::mem::transmute::<usize, IrqHandler>(handler);
+            handler();
+        }
+
+        end_of_interrupt(irq);
+    }
+}
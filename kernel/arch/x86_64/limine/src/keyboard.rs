@@ -1,23 +1,63 @@
 //! PS/2 keyboard driver (IRQ1, scancode set 1).
 //!
 //! Provides a ring buffer that the IRQ handler fills with ASCII characters.
-//! The kernel can poll with [`try_read_char`] or spin with [`read_char`].
+//! The kernel can poll with [`try_read_char`] or wait with [`read_char`],
+//! which sleeps on a wait queue the IRQ handler wakes.
 
-use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::pic;
+use crate::irq;
 use crate::port::inb;
+use crate::sync::IrqSafeMutex;
+use crate::wait::WaitQueue;
+
+/// The PS/2 keyboard raises IRQ1.
+const KEYBOARD_IRQ: u8 = 1;
 
 // ── Ring buffer for received characters ─────────────────────────────
 
 const BUF_SIZE: usize = 256;
 
-/// Ring buffer storage.  Written only from the IRQ handler (single-producer).
-static mut KEY_BUF: [u8; BUF_SIZE] = [0; BUF_SIZE];
-/// Write index (only modified by the IRQ handler).
-static WRITE_IDX: AtomicUsize = AtomicUsize::new(0);
-/// Read index (only modified by the consumer).
-static READ_IDX: AtomicUsize = AtomicUsize::new(0);
+/// Characters received but not yet read.  Filled by the IRQ handler,
+/// drained by the reader.
+struct KeyBuffer {
+    buf: [u8; BUF_SIZE],
+    read_idx: usize,
+    write_idx: usize,
+}
+
+impl KeyBuffer {
+    /// Append `ch`, dropping it if the buffer is full.
+    fn push(&mut self, ch: u8) {
+        let next = (self.write_idx + 1) % BUF_SIZE;
+        if next != self.read_idx {
+            self.buf[self.write_idx] = ch;
+            self.write_idx = next;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.read_idx == self.write_idx
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let ch = self.buf[self.read_idx];
+        self.read_idx = (self.read_idx + 1) % BUF_SIZE;
+        Some(ch)
+    }
+}
+
+static KEY_BUF: IrqSafeMutex<KeyBuffer> = IrqSafeMutex::new(KeyBuffer {
+    buf: [0; BUF_SIZE],
+    read_idx: 0,
+    write_idx: 0,
+});
+
+/// Readers waiting for the buffer to fill.
+static KEY_WAIT: WaitQueue = WaitQueue::new();
 
 // ── Shift / modifier tracking ───────────────────────────────────────
 
@@ -52,95 +92,85 @@ static SCANCODE_TO_ASCII_SHIFT: [u8; 58] = [
     b'*', 0, b' ',                                                 // 0x37-0x39
 ];
 
-// ── IRQ handler (called from the IDT stub) ──────────────────────────
+// ── IRQ handler (registered with the IRQ dispatcher) ────────────────
 
-/// Called by the assembly IRQ stub.  Reads the scancode, translates it,
+/// Called by `irq::dispatch` on IRQ1.  Reads the scancode, translates it,
 /// and pushes printable characters into the ring buffer.
-#[unsafe(no_mangle)]
-pub extern "C" fn keyboard_irq_handler() {
-    // SAFETY: we are inside an interrupt with IF=0, so no preemption.
-    unsafe {
-        let scancode = inb(0x60);
-
-        // Key release (bit 7 set)?
-        if scancode & 0x80 != 0 {
-            let released = scancode & 0x7F;
-            // Left Shift = 0x2A, Right Shift = 0x36
-            if released == 0x2A || released == 0x36 {
-                SHIFT_HELD.store(false, Ordering::Relaxed);
+fn keyboard_irq_handler() {
+    // SAFETY: reading the PS/2 data port acknowledges the byte.
+    let scancode = unsafe { inb(0x60) };
+
+    // Key release (bit 7 set)?
+    if scancode & 0x80 != 0 {
+        let released = scancode & 0x7F;
+        // Left Shift = 0x2A, Right Shift = 0x36
+        if released == 0x2A || released == 0x36 {
+            SHIFT_HELD.store(false, Ordering::Relaxed);
+        }
+    } else {
+        // Key press
+        match scancode {
+            0x2A | 0x36 => {
+                SHIFT_HELD.store(true, Ordering::Relaxed);
             }
-        } else {
-            // Key press
-            match scancode {
-                0x2A | 0x36 => {
-                    SHIFT_HELD.store(true, Ordering::Relaxed);
-                }
-                0x3A => {
-                    // Caps Lock toggle
-                    let prev = CAPS_LOCK.load(Ordering::Relaxed);
-                    CAPS_LOCK.store(!prev, Ordering::Relaxed);
-                }
-                _ => {
-                    if (scancode as usize) < SCANCODE_TO_ASCII.len() {
-                        let shifted = SHIFT_HELD.load(Ordering::Relaxed);
-                        let caps = CAPS_LOCK.load(Ordering::Relaxed);
-
-                        let mut ch = if shifted {
-                            SCANCODE_TO_ASCII_SHIFT[scancode as usize]
+            0x3A => {
+                // Caps Lock toggle
+                let prev = CAPS_LOCK.load(Ordering::Relaxed);
+                CAPS_LOCK.store(!prev, Ordering::Relaxed);
+            }
+            _ => {
+                if (scancode as usize) < SCANCODE_TO_ASCII.len() {
+                    let shifted = SHIFT_HELD.load(Ordering::Relaxed);
+                    let caps = CAPS_LOCK.load(Ordering::Relaxed);
+
+                    let mut ch = if shifted {
+                        SCANCODE_TO_ASCII_SHIFT[scancode as usize]
+                    } else {
+                        SCANCODE_TO_ASCII[scancode as usize]
+                    };
+
+                    // Caps Lock only affects letters
+                    if caps && ch.is_ascii_alphabetic() {
+                        ch = if shifted {
+                            ch.to_ascii_lowercase()
                         } else {
-                            SCANCODE_TO_ASCII[scancode as usize]
+                            ch.to_ascii_uppercase()
                         };
+                    }
 
-                        // Caps Lock only affects letters
-                        if caps && ch.is_ascii_alphabetic() {
-                            ch = if shifted {
-                                ch.to_ascii_lowercase()
-                            } else {
-                                ch.to_ascii_uppercase()
-                            };
-                        }
-
-                        if ch != 0 {
-                            let w = WRITE_IDX.load(Ordering::Relaxed);
-                            let next = (w + 1) % BUF_SIZE;
-                            // Drop the character if the buffer is full.
-                            if next != READ_IDX.load(Ordering::Relaxed) {
-                                KEY_BUF[w] = ch;
-                                WRITE_IDX.store(next, Ordering::Relaxed);
-                            }
-                        }
+                    if ch != 0 {
+                        KEY_BUF.lock().push(ch);
+                        KEY_WAIT.wake_all();
                     }
                 }
             }
         }
-
-        pic::send_eoi(1);
     }
 }
 
 // ── Public API ──────────────────────────────────────────────────────
 
+/// Register the keyboard IRQ handler, which also unmasks IRQ1.
+pub fn init() {
+    // Registration only fails if IRQ1 is already taken, in which case the
+    // existing handler keeps working.
+    let _ = irq::register_irq_handler(KEYBOARD_IRQ, keyboard_irq_handler);
+}
+
 /// Try to read one character from the keyboard buffer.
 /// Returns `None` immediately if the buffer is empty.
 pub fn try_read_char() -> Option<u8> {
-    let r = READ_IDX.load(Ordering::Acquire);
-    let w = WRITE_IDX.load(Ordering::Acquire);
-    if r == w {
-        return None;
-    }
-    // SAFETY: only one consumer; IRQ handler only advances WRITE_IDX.
-    let ch = unsafe { KEY_BUF[r] };
-    READ_IDX.store((r + 1) % BUF_SIZE, Ordering::Release);
-    Some(ch)
+    KEY_BUF.lock().pop()
 }
 
-/// Block until a character is available, then return it.
+/// Block until a character is available, then return it.  The calling
+/// thread sleeps while the buffer is empty.
 pub fn read_char() -> u8 {
     loop {
         if let Some(ch) = try_read_char() {
             return ch;
         }
-        core::hint::spin_loop();
+        KEY_WAIT.wait_event(|| !KEY_BUF.lock().is_empty());
     }
 }
 
@@ -1,20 +1,53 @@
 #![no_std]
+#![feature(alloc_error_handler)]
 #![crate_name = "limine"]
 #![allow(non_camel_case_types, non_upper_case_globals)]
 
+extern crate alloc;
+
 mod bindings;
+pub mod acpi;
+pub mod addr;
+pub mod apic;
+pub mod blocking;
+pub mod console;
+pub mod cpu;
+pub mod exceptions;
+pub mod gdt;
+pub mod heap;
+pub mod hpet;
+pub mod interrupts;
+pub mod irq;
 pub mod keyboard;
+pub mod mmio;
+pub mod msr;
+pub mod paging;
+pub mod percpu;
 pub mod pic;
+pub mod pit;
+pub mod pmm;
 pub mod port;
+pub mod sched;
+pub mod slab;
+pub mod smp;
+pub mod stack;
+pub mod sync;
+pub mod thread;
+pub mod tsc;
+pub mod uaccess;
+pub mod wait;
 
 pub use bindings::*;
 
 use core::cell::UnsafeCell;
-use core::mem::MaybeUninit;
+use core::mem::{ManuallyDrop, MaybeUninit};
 use core::ptr;
 use core::sync::atomic::{AtomicBool, Ordering};
 
-// ── Default exception handler (assembly) ────────────────────────────
+use addr::{PhysAddr, VirtAddr};
+use stack::KernelStack;
+
+// ── Default interrupt handler (assembly) ────────────────────────────
 
 core::arch::global_asm!(
     "_default_exception_handler:",
@@ -23,70 +56,8 @@ core::arch::global_asm!(
     "jmp 2b",
 );
 
-// IRQ1 (keyboard) stub: skips the red zone, saves all GP registers,
-// calls the Rust handler, restores registers, then returns from interrupt.
-core::arch::global_asm!(
-    "_keyboard_irq_stub:",
-    // Skip past the 128-byte red zone (x86_64 SysV ABI).
-    "sub rsp, 128",
-    // Save all general-purpose registers
-    "push rax",
-    "push rcx",
-    "push rdx",
-    "push rbx",
-    "push rbp",
-    "push rsi",
-    "push rdi",
-    "push r8",
-    "push r9",
-    "push r10",
-    "push r11",
-    "push r12",
-    "push r13",
-    "push r14",
-    "push r15",
-    // Call the Rust handler
-    "call keyboard_irq_handler",
-    // Restore all general-purpose registers
-    "pop r15",
-    "pop r14",
-    "pop r13",
-    "pop r12",
-    "pop r11",
-    "pop r10",
-    "pop r9",
-    "pop r8",
-    "pop rdi",
-    "pop rsi",
-    "pop rbp",
-    "pop rbx",
-    "pop rdx",
-    "pop rcx",
-    "pop rax",
-    // Restore RSP past the red zone
-    "add rsp, 128",
-    "iretq",
-);
-
-// Dummy handler for IRQ vectors we don't care about (e.g. timer).
-// Sends EOI to both PICs and returns — prevents the default halt handler
-// from freezing the system when a spurious or unhandled IRQ fires.
-core::arch::global_asm!(
-    "_dummy_irq_stub:",
-    "push rax",
-    // Send EOI to slave PIC (in case IRQ >= 8)
-    "mov al, 0x20",
-    "out 0xA0, al",
-    // Send EOI to master PIC
-    "out 0x20, al",
-    "pop rax",
-    "iretq",
-);
-
 unsafe extern "C" {
     fn _default_exception_handler();
-    fn _keyboard_irq_stub();
-    fn _dummy_irq_stub();
 }
 
 // ── IDT types ───────────────────────────────────────────────────────
@@ -125,6 +96,12 @@ impl IdtEntry {
         self.type_attr = 0x8E; // Present, DPL=0, 64-bit Interrupt Gate
         self.reserved = 0;
     }
+
+    /// Run this handler on the TSS Interrupt Stack Table entry `index` (1-7)
+    /// instead of the interrupted stack.
+    fn set_ist(&mut self, index: u8) {
+        self.ist = index & 0x7;
+    }
 }
 
 #[repr(C, packed)]
@@ -140,34 +117,57 @@ unsafe impl Sync for IdtCell {}
 static IDT: IdtCell = IdtCell(UnsafeCell::new([IdtEntry::empty(); 256]));
 
 /// Populate every IDT slot with a default "halt" handler, install the
-/// keyboard IRQ handler at vector 0x21, and load the IDT.
+/// CPU exception stubs at vectors 0-31, the IRQ trampolines at vectors
+/// 0x20-0x37, the APIC spurious-interrupt stub and the SMP wake-up stub,
+/// and load the IDT.
+///
+/// Expects the kernel GDT and TSS from [`gdt::init`] to be loaded.
 unsafe fn setup_idt() {
     let handler = _default_exception_handler as *const () as u64;
 
     // SAFETY: single-threaded init context; no other references to IDT exist.
     let idt = unsafe { &mut *IDT.0.get() };
 
-    // Fill all 256 entries — CS selector 0x28 is the 64-bit code segment
-    // set up by Limine's GDT.
+    // Fill all 256 entries with the kernel's 64-bit code segment.
     for entry in idt.iter_mut() {
-        entry.set_handler(handler, 0x28);
+        entry.set_handler(handler, gdt::KERNEL_CODE_SELECTOR);
+    }
+
+    // Give each CPU exception its own stub so faults are reported by name.
+    for (vec, entry) in idt.iter_mut().enumerate().take(32) {
+        entry.set_handler(exceptions::stub_address(vec), gdt::KERNEL_CODE_SELECTOR);
     }
 
-    // Install a dummy "send-EOI-and-return" handler for all 16 IRQ vectors
-    // (0x20-0x2F) so that unhandled IRQs (like the PIT timer on IRQ0)
-    // don't fall through to the default halt handler.
-    let dummy = _dummy_irq_stub as *const () as u64;
-    for vec in 0x20..=0x2F {
-        idt[vec].set_handler(dummy, 0x28);
+    // #DF, NMI and #MC can arrive with a corrupt or exhausted stack, so
+    // they always switch to their own known-good IST stack.  A kernel stack
+    // overflow shows up as a #DF: the #PF on the guard page cannot push its
+    // frame on the exhausted stack.
+    idt[8].set_ist(gdt::DOUBLE_FAULT_IST);
+    idt[2].set_ist(gdt::NMI_IST);
+    idt[18].set_ist(gdt::MACHINE_CHECK_IST);
+
+    // Route all IRQ vectors (0x20-0x37) through the generic trampolines;
+    // `irq::dispatch` calls whichever driver registered for the line and
+    // sends the EOI, so unhandled IRQs (like the PIT timer on IRQ0) don't
+    // fall through to the default halt handler.
+    for irq in 0..irq::IRQ_COUNT {
+        idt[irq::IRQ_BASE_VECTOR as usize + irq].set_handler(irq::stub_address(irq), gdt::KERNEL_CODE_SELECTOR);
     }
 
-    // Install the keyboard IRQ handler at vector 0x21 (IRQ1 after PIC remap).
-    let kb_handler = _keyboard_irq_stub as *const () as u64;
-    idt[0x21].set_handler(kb_handler, 0x28);
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler(apic::spurious_stub_address(), gdt::KERNEL_CODE_SELECTOR);
+    idt[apic::TIMER_VECTOR as usize].set_handler(apic::timer_stub_address(), gdt::KERNEL_CODE_SELECTOR);
+    idt[smp::WAKE_VECTOR as usize].set_handler(smp::wake_stub_address(), gdt::KERNEL_CODE_SELECTOR);
+
+    unsafe { load_idt(); }
+}
 
+/// Load the (already populated) IDT on the calling CPU.
+///
+/// Expects the CPU's GDT and TSS from [`gdt::init`] to be loaded.
+unsafe fn load_idt() {
     let idt_ptr = IdtPtr {
         limit: (core::mem::size_of::<[IdtEntry; 256]>() - 1) as u16,
-        base: idt.as_ptr() as u64,
+        base: IDT.0.get() as u64,
     };
 
     // SAFETY: idt_ptr points to a valid, fully-initialised IDT.
@@ -259,6 +259,113 @@ pub static limine_framebuffer_request: VolatileCell<limine_framebuffer_request>
         response: ptr::null_mut(),
     });
 
+// ── HHDM request ────────────────────────────────────────────────────
+
+#[used]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".limine_requests")]
+static limine_hhdm_request: VolatileCell<limine_hhdm_request> =
+    VolatileCell::new(limine_hhdm_request {
+        id: [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x48dcf1cb8ad2b852,
+            0x63984e959a98244b,
+        ],
+        revision: 0,
+        response: ptr::null_mut(),
+    });
+
+// ── RSDP request ────────────────────────────────────────────────────
+
+#[used]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".limine_requests")]
+static limine_rsdp_request: VolatileCell<limine_rsdp_request> =
+    VolatileCell::new(limine_rsdp_request {
+        id: [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0xc5e77b6b397e7b43,
+            0x27637845accdcf3c,
+        ],
+        revision: 0,
+        response: ptr::null_mut(),
+    });
+
+// ── Executable address request ──────────────────────────────────────
+
+#[used]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".limine_requests")]
+static limine_executable_address_request: VolatileCell<limine_executable_address_request> =
+    VolatileCell::new(limine_executable_address_request {
+        id: [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x71ba76863cc55f63,
+            0xb2644a48c516a487,
+        ],
+        revision: 0,
+        response: ptr::null_mut(),
+    });
+
+// ── Memory map request ──────────────────────────────────────────────
+
+#[used]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".limine_requests")]
+static limine_memmap_request: VolatileCell<limine_memmap_request> =
+    VolatileCell::new(limine_memmap_request {
+        id: [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x67cf3d9d378a806f,
+            0xe304acdfc50c3c62,
+        ],
+        revision: 0,
+        response: ptr::null_mut(),
+    });
+
+// ── MP request ──────────────────────────────────────────────────────
+
+#[used]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".limine_requests")]
+static limine_mp_request: VolatileCell<limine_mp_request> =
+    VolatileCell::new(limine_mp_request {
+        id: [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x95a67b819a1b857e,
+            0xa0b61b723b6a73e0,
+        ],
+        revision: 0,
+        response: ptr::null_mut(),
+        flags: 0,
+    });
+
+// ── Stack size request ──────────────────────────────────────────────
+
+/// Size of the boot stack the BSP runs on.
+const BOOT_STACK_SIZE: u64 = 64 * 1024;
+
+#[used]
+#[unsafe(no_mangle)]
+#[unsafe(link_section = ".limine_requests")]
+static limine_stack_size_request: VolatileCell<limine_stack_size_request> =
+    VolatileCell::new(limine_stack_size_request {
+        id: [
+            0xc7b1dd30df4c8b88,
+            0x0a82e883a194f07b,
+            0x224ef0460a8e8926,
+            0xe1cb0fc25f46ea3d,
+        ],
+        revision: 0,
+        response: ptr::null_mut(),
+        stack_size: BOOT_STACK_SIZE,
+    });
+
 // ── Request section markers ─────────────────────────────────────────
 
 #[used]
@@ -279,6 +386,63 @@ static limine_requests_end_marker: VolatileCell<[u64; 2]> = VolatileCell::new([
     0x9572709f31764c62,
 ]);
 
+// ── Boot information accessors ──────────────────────────────────────
+//
+// The responses live in bootloader-reclaimable memory, so none of these may
+// be called once `finish_init` has released it.
+
+/// Virtual offset of the higher-half direct map of physical memory.
+pub fn hhdm_offset() -> Option<u64> {
+    // SAFETY: the response is written by the bootloader before we run.
+    unsafe {
+        let response = (*limine_hhdm_request.0.get()).response;
+        if response.is_null() { None } else { Some((*response).offset) }
+    }
+}
+
+/// Physical address of the ACPI RSDP, if the firmware provides one.
+pub fn rsdp_address() -> Option<PhysAddr> {
+    // SAFETY: the response is written by the bootloader before we run.
+    unsafe {
+        let response = (*limine_rsdp_request.0.get()).response;
+        if response.is_null() || (*response).address.is_null() {
+            None
+        } else {
+            Some(PhysAddr::new((*response).address as u64))
+        }
+    }
+}
+
+/// Physical and virtual base address the kernel image was loaded at.
+pub fn executable_address() -> Option<(PhysAddr, VirtAddr)> {
+    // SAFETY: the response is written by the bootloader before we run.
+    unsafe {
+        let response = (*limine_executable_address_request.0.get()).response;
+        if response.is_null() {
+            None
+        } else {
+            Some((PhysAddr::new((*response).physical_base), VirtAddr::new((*response).virtual_base)))
+        }
+    }
+}
+
+/// Entries of the physical memory map, sorted by base address.
+pub fn memmap_entries() -> Option<impl Iterator<Item = &'static limine_memmap_entry> + Clone> {
+    // SAFETY: the response is written by the bootloader before we run, and
+    // lives in bootloader-reclaimable memory that is never freed while in
+    // use.
+    unsafe {
+        let response = (*limine_memmap_request.0.get()).response.as_ref()?;
+        let entries = core::slice::from_raw_parts(response.entries, response.entry_count as usize);
+        Some(entries.iter().map(|&entry| &*entry))
+    }
+}
+
+/// The MP response listing every CPU, if the bootloader provided one.
+fn mp_response() -> Option<&'static limine_mp_response> {
+    // SAFETY: the response is written by the bootloader before we run.
+    unsafe { (*limine_mp_request.0.get()).response.as_ref() }
+}
 
 /// Halt and catch fire — loops forever.
 fn hcf() -> ! {
@@ -287,14 +451,73 @@ fn hcf() -> ! {
     }
 }
 
-pub fn init() {
+/// Bring the kernel up, then run `main` as the BSP's first thread.
+///
+/// Limine starts us on a boot stack with no guard page below it, so as soon
+/// as stacks can be allocated we move to a guarded [`KernelStack`] owned by
+/// that thread, and never return to the boot stack.
+pub fn init(main: fn() -> !) -> ! {
     // SAFETY: These statics are written by the bootloader before we run.
     // We only read them here, in single-threaded init context.
     unsafe {
-        // Set up the IDT first, so any subsequent exception is caught
-        // instead of causing a triple fault.
+        // Cache the HHDM offset and kernel load address for address
+        // conversions.
+        addr::init();
+
+        // Replace Limine's GDT with our own (including the TSS and its IST
+        // stacks) and point GS at the per-CPU area the interrupt path
+        // relies on, then set up the IDT, so any subsequent exception is
+        // caught instead of causing a triple fault.
+        gdt::init(0);
+        percpu::init(0);
         setup_idt();
 
+        // Identify the CPU and enable XSAVE/AVX state, which the interrupt
+        // path saves from now on.
+        cpu::init();
+        interrupts::init_extended_state();
+
+        // Build the physical frame allocator from the memory map, move
+        // off Limine's page tables onto our own and map the kernel heap.
+        pmm::init();
+        paging::init();
+        heap::init();
+
+        // Keep the kernel from executing or touching user pages outside
+        // the uaccess helpers.
+        uaccess::init();
+    }
+
+    let Some(stack) = KernelStack::new(thread::THREAD_STACK_SIZE, "main") else {
+        panic!("init: out of memory allocating the main stack");
+    };
+    let top = stack.top().as_u64();
+    let stack = ManuallyDrop::new(stack);
+    // SAFETY: the new stack is mapped and unused.  `finish_init` takes
+    // `stack` over, and this frame is never returned to.
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "call {finish_init}",
+            top = in(reg) top,
+            finish_init = sym finish_init,
+            in("rdi") &*stack as *const KernelStack,
+            in("rsi") main as usize,
+            options(noreturn),
+        );
+    }
+}
+
+/// Rest of [`init`], on the main thread's own stack.
+extern "C" fn finish_init(stack: *const KernelStack, main: usize) -> ! {
+    // SAFETY: `init` passes a stack it gave up and a `fn() -> !` address.
+    let (stack, main) = unsafe { (ptr::read(stack), core::mem::transmute::<usize, fn() -> !>(main)) };
+
+    // SAFETY: as in `init`; we are still single-threaded.
+    unsafe {
+        // Adopt the boot flow as the BSP's first kernel thread.
+        thread::init("main", Some(stack));
+
         // Ensure the bootloader understands our base revision (see spec).
         // The bootloader zeroes element [2] to signal support.
         let base_rev = &*limine_base_revision.0.get();
@@ -321,13 +544,46 @@ pub fn init() {
         });
         FB_INIT.store(true, Ordering::Release);
 
-        // Initialise the 8259 PIC: remap IRQs to vectors 0x20-0x2F,
-        // mask everything, then unmask IRQ1 (keyboard).
+        // Initialise the 8259 PIC: remap IRQs to vectors 0x20-0x2F and
+        // mask everything. Drivers unmask their line when they register.
         pic::mask_all();
         pic::init();
-        pic::unmask_irq(1);
+
+        // Switch to the local APIC + I/O APIC if the MADT describes them.
+        // The 8259 then stays fully masked; otherwise it remains in charge.
+        apic::init();
+
+        // Start the HPET main counter, if there is one.  Its comparators
+        // are only usable through the I/O APIC.
+        hpet::init();
+
+        keyboard::init();
+
+        // Start the system tick on IRQ0, which also drives preemption and
+        // the timeouts of blocking waits.  This can only fail if something
+        // else already claimed IRQ0.
+        if pit::init(pit::DEFAULT_FREQUENCY_HZ).is_err() {
+            console::println(b"pit: IRQ0 is taken; no preemption, and timed waits will panic");
+        }
+
+        // Calibrate the TSC while interrupts are still off, then the
+        // local APIC timer against it: the PIT only interrupts the BSP, so
+        // that timer is what ticks the APs.
+        tsc::init();
+        apic::calibrate_timer(pit::DEFAULT_FREQUENCY_HZ);
+
+        // Bring up the application processors; they park in their idle
+        // loops until work is posted to them.
+        smp::init();
+
+        // Every CPU now runs on our own stacks and page tables, and what we
+        // need from Limine's responses has been copied out, so give the
+        // bootloader's memory to the frame allocator.
+        pmm::release_bootloader_memory();
 
         // Enable hardware interrupts so the keyboard IRQ fires.
         core::arch::asm!("sti", options(nomem, nostack));
     }
+
+    main()
 }
\ No newline at end of file
@@ -0,0 +1,40 @@
+//! Mapping of device MMIO and firmware tables.
+//!
+//! Since base revision 3, Limine's higher-half direct map only covers the
+//! memory-map entries it reports, and the kernel's own tables (see
+//! [`crate::paging`]) follow suit, so device registers (LAPIC, I/O APIC,
+//! HPET) and firmware tables living in reserved memory are not reachable.
+//! This module maps such regions into the kernel address space at their
+//! usual HHDM address.
+
+use crate::addr::{phys_to_virt, PhysAddr, VirtAddr};
+use crate::paging::{kernel_space, CacheType, PageFlags, PageSize};
+
+fn map_range(phys: PhysAddr, len: u64, cache: CacheType) -> Option<VirtAddr> {
+    let start = phys.align_down(PageSize::Size4K.bytes());
+    let end = (phys + len).align_up(PageSize::Size4K.bytes());
+    let flags = PageFlags::KERNEL_DATA.with_cache(cache);
+
+    // Pages that are already mapped, e.g. ACPI tables in the HHDM, are
+    // left as they are.
+    kernel_space().map_range(phys_to_virt(start), start, end - start, flags).ok()?;
+    Some(phys_to_virt(phys))
+}
+
+/// Map device registers at `phys..phys + len` uncached and return their
+/// virtual address.
+///
+/// # Safety
+/// `phys..phys + len` must not be RAM the kernel uses as ordinary memory.
+pub unsafe fn map_uncached(phys: PhysAddr, len: u64) -> Option<VirtAddr> {
+    map_range(phys, len, CacheType::Uncached)
+}
+
+/// Map ordinary memory (e.g. ACPI tables) at `phys..phys + len` write-back
+/// cached and return its virtual address.
+///
+/// # Safety
+/// `phys..phys + len` must not be device memory.
+pub unsafe fn map_cached(phys: PhysAddr, len: u64) -> Option<VirtAddr> {
+    map_range(phys, len, CacheType::WriteBack)
+}
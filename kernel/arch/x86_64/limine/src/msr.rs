@@ -0,0 +1,37 @@
+//! x86_64 model-specific register helpers.
+
+/// Read a 64-bit model-specific register.
+///
+/// # Safety
+/// `msr` must exist on this CPU, otherwise `rdmsr` raises #GP.
+#[inline]
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!(
+            "rdmsr",
+            in("ecx") msr,
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// Write a 64-bit model-specific register.
+///
+/// # Safety
+/// `msr` must exist on this CPU and `value` must be valid for it.
+#[inline]
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    unsafe {
+        core::arch::asm!(
+            "wrmsr",
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            options(nostack, preserves_flags),
+        );
+    }
+}
@@ -0,0 +1,712 @@
+//! 4-level page table management.
+//!
+//! An [`AddressSpace`] owns a PML4 and maps 4 KiB, 2 MiB and 1 GiB pages
+//! with explicit [`PageFlags`].  Page-table pages come from the frame
+//! allocator and are reached through the HHDM.
+//!
+//! [`init`] builds the kernel's own address space to replace the one Limine
+//! handed over: the HHDM over every memory-map entry (with the largest
+//! pages that fit) and the kernel image, one mapping per PHDR of
+//! `linker.ld` with that segment's permissions.  Memory outside the memory
+//! map (device registers, firmware tables) is added on demand by
+//! [`crate::mmio`].
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::addr::{phys_to_virt, virt_to_phys, PhysAddr, VirtAddr};
+use crate::cpu::{self, Feature};
+use crate::msr::{rdmsr, wrmsr};
+use crate::pmm;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+use crate::{
+    memmap_entries, LIMINE_MEMMAP_BAD_MEMORY, LIMINE_MEMMAP_FRAMEBUFFER, LIMINE_MEMMAP_RESERVED,
+};
+
+const IA32_EFER: u32 = 0xC000_0080;
+const IA32_PAT: u32 = 0x277;
+const EFER_NXE: u64 = 1 << 11;
+const CR0_WP: u64 = 1 << 16;
+const CR4_PGE: u64 = 1 << 7;
+
+const ENTRIES: usize = 512;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_WRITE_THROUGH: u64 = 1 << 3;
+const PTE_CACHE_DISABLE: u64 = 1 << 4;
+const PTE_HUGE: u64 = 1 << 7;
+const PTE_GLOBAL: u64 = 1 << 8;
+/// PAT bit of a 4 KiB entry; in 2 MiB and 1 GiB entries bit 7 is
+/// [`PTE_HUGE`] and the PAT bit moves to bit 12.
+const PTE_PAT: u64 = 1 << 7;
+const PTE_PAT_HUGE: u64 = 1 << 12;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The PAT as the kernel programs it: the power-on layout (WB, WT, UC-,
+/// UC, twice) with entry 4 changed to write-combining.
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+/// Set once EFER.NXE is on; until then (or on CPUs without NX) bit 63 is
+/// reserved and [`PageFlags::no_execute`] is not encoded.
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set once entries are encoded for [`PAT_VALUE`], which the PAT holds
+/// from the moment the kernel's tables go live; until then (or on CPUs
+/// without a PAT) [`CacheType::WriteCombining`] falls back to
+/// uncached-minus.
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Page sizes supported by the mapper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    /// Size in bytes.
+    pub const fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4K => 0x1000,
+            PageSize::Size2M => 0x20_0000,
+            PageSize::Size1G => 0x4000_0000,
+        }
+    }
+
+    /// Level of the table holding the leaf entry (1 = PT, 4 = PML4).
+    const fn level(self) -> usize {
+        match self {
+            PageSize::Size4K => 1,
+            PageSize::Size2M => 2,
+            PageSize::Size1G => 3,
+        }
+    }
+}
+
+/// Memory type of a mapping.
+///
+/// The variants are PAT entries 0-4 of [`PAT_VALUE`]; entries 0-3 keep
+/// their power-on types, so they mean the same under Limine's PAT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    WriteThrough,
+    /// Uncached, but a write-combining MTRR still applies.
+    UncachedMinus,
+    Uncached,
+    /// Uncached reads, writes buffered and merged into bursts; for
+    /// framebuffers.
+    WriteCombining,
+}
+
+impl CacheType {
+    fn pat_index(self) -> u64 {
+        match self {
+            CacheType::WriteBack => 0,
+            CacheType::WriteThrough => 1,
+            CacheType::UncachedMinus => 2,
+            CacheType::Uncached => 3,
+            CacheType::WriteCombining if PAT_ENABLED.load(Ordering::Relaxed) => 4,
+            CacheType::WriteCombining => 2,
+        }
+    }
+
+    const fn from_pat_index(index: u64) -> Self {
+        match index {
+            4 => CacheType::WriteCombining,
+            _ => match index & 3 {
+                0 => CacheType::WriteBack,
+                1 => CacheType::WriteThrough,
+                2 => CacheType::UncachedMinus,
+                _ => CacheType::Uncached,
+            },
+        }
+    }
+}
+
+/// Permissions and memory type of a mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageFlags {
+    pub writable: bool,
+    /// Accessible from ring 3.
+    pub user: bool,
+    pub no_execute: bool,
+    /// Kept in the TLB across CR3 switches; for kernel mappings only.
+    pub global: bool,
+    pub cache: CacheType,
+}
+
+impl PageFlags {
+    /// Kernel code: read-only, executable.
+    pub const KERNEL_CODE: Self = Self {
+        writable: false,
+        user: false,
+        no_execute: false,
+        global: true,
+        cache: CacheType::WriteBack,
+    };
+    /// Kernel constants: read-only, not executable.
+    pub const KERNEL_RODATA: Self = Self { no_execute: true, ..Self::KERNEL_CODE };
+    /// Kernel data: writable, not executable.
+    pub const KERNEL_DATA: Self = Self { writable: true, ..Self::KERNEL_RODATA };
+
+    /// The same permissions with memory type `cache`.
+    pub const fn with_cache(self, cache: CacheType) -> Self {
+        Self { cache, ..self }
+    }
+
+    fn encode(self, size: PageSize) -> u64 {
+        let mut bits = PTE_PRESENT;
+        if self.writable {
+            bits |= PTE_WRITABLE;
+        }
+        if self.user {
+            bits |= PTE_USER;
+        }
+        if self.global {
+            bits |= PTE_GLOBAL;
+        }
+        if self.no_execute && NX_ENABLED.load(Ordering::Relaxed) {
+            bits |= PTE_NO_EXECUTE;
+        }
+
+        let pat = self.cache.pat_index();
+        if pat & 1 != 0 {
+            bits |= PTE_WRITE_THROUGH;
+        }
+        if pat & 2 != 0 {
+            bits |= PTE_CACHE_DISABLE;
+        }
+        if pat & 4 != 0 {
+            bits |= if size == PageSize::Size4K { PTE_PAT } else { PTE_PAT_HUGE };
+        }
+        if size != PageSize::Size4K {
+            bits |= PTE_HUGE;
+        }
+        bits
+    }
+
+    fn decode(entry: u64, size: PageSize) -> Self {
+        let pat_bit = if size == PageSize::Size4K { PTE_PAT } else { PTE_PAT_HUGE };
+        let pat = (entry & PTE_WRITE_THROUGH != 0) as u64
+            | ((entry & PTE_CACHE_DISABLE != 0) as u64) << 1
+            | ((entry & pat_bit != 0) as u64) << 2;
+        Self {
+            writable: entry & PTE_WRITABLE != 0,
+            user: entry & PTE_USER != 0,
+            no_execute: entry & PTE_NO_EXECUTE != 0,
+            global: entry & PTE_GLOBAL != 0,
+            cache: CacheType::from_pat_index(pat),
+        }
+    }
+}
+
+/// Errors returned by the mapper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// The frame allocator ran out of memory for a page-table page.
+    OutOfMemory,
+    /// An address is not aligned to the page size.
+    Misaligned,
+    /// Something is already mapped at the address.
+    AlreadyMapped,
+    /// Nothing is mapped at the address.
+    NotMapped,
+    /// A larger page already covers the address.
+    HugePageConflict,
+    /// The CPU does not support 1 GiB pages.
+    HugePagesUnsupported,
+}
+
+/// The result of a successful [`AddressSpace::translate`].
+#[derive(Clone, Copy, Debug)]
+pub struct Translation {
+    /// Physical address the virtual address maps to.
+    pub phys: PhysAddr,
+    /// Size of the page containing it.
+    pub size: PageSize,
+    pub flags: PageFlags,
+}
+
+/// A set of page tables rooted at a PML4.
+pub struct AddressSpace {
+    pml4: PhysAddr,
+}
+
+/// The kernel's address space, shared by every CPU.
+static KERNEL_SPACE: IrqSafeMutex<AddressSpace> =
+    IrqSafeMutex::new(AddressSpace { pml4: PhysAddr::new(0) });
+
+fn table(phys: PhysAddr) -> *mut u64 {
+    phys_to_virt(phys).as_mut_ptr()
+}
+
+/// Index of `virt` in its table at `level`.
+fn index(virt: VirtAddr, level: usize) -> usize {
+    ((virt.as_u64() >> (12 + 9 * (level - 1))) & 0x1FF) as usize
+}
+
+/// Allocate a zeroed page-table page.
+fn alloc_table() -> Result<PhysAddr, MapError> {
+    let phys = pmm::alloc_frame().ok_or(MapError::OutOfMemory)?;
+    // SAFETY: the frame is fresh from the allocator and mapped by the HHDM.
+    unsafe { core::ptr::write_bytes(table(phys), 0, ENTRIES) };
+    Ok(phys)
+}
+
+/// Free the page-table page at `phys`, a table at `level`, and every table
+/// below it.  The frames its leaf entries map are left alone.
+///
+/// # Safety
+/// The tables must not be in use by any CPU.
+unsafe fn free_tables(phys: PhysAddr, level: usize) {
+    if level > 1 {
+        let current = table(phys);
+        for i in 0..ENTRIES {
+            // SAFETY: `current` is a page-table page and `i` is < 512.
+            let entry = unsafe { *current.add(i) };
+            if entry & PTE_PRESENT != 0 && (level == 4 || entry & PTE_HUGE == 0) {
+                unsafe { free_tables(PhysAddr::new(entry & PTE_ADDR_MASK), level - 1) };
+            }
+        }
+    }
+    pmm::free_frame(phys);
+}
+
+fn read_cr0() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+unsafe fn write_cr0(value: u64) {
+    unsafe {
+        core::arch::asm!("mov cr0, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+fn read_cr3() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    unsafe {
+        core::arch::asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+/// Invalidate the TLB entry for `virt` on the calling CPU.
+pub fn flush(virt: VirtAddr) {
+    // SAFETY: `invlpg` has no effect beyond the TLB.
+    unsafe {
+        core::arch::asm!("invlpg [{}]", in(reg) virt.as_u64(), options(nostack, preserves_flags));
+    }
+}
+
+/// Invalidate every TLB entry on the calling CPU, global ones included.
+pub fn flush_all() {
+    let cr4 = read_cr4();
+    // SAFETY: toggling PGE (or reloading CR3) only flushes the TLB.
+    unsafe {
+        if cr4 & CR4_PGE != 0 {
+            write_cr4(cr4 & !CR4_PGE);
+            write_cr4(cr4);
+        } else {
+            core::arch::asm!("mov cr3, {0}", in(reg) read_cr3(), options(nostack, preserves_flags));
+        }
+    }
+}
+
+impl AddressSpace {
+    /// Create an address space with an empty lower half, sharing the
+    /// kernel's upper-half tables.
+    pub fn new() -> Result<Self, MapError> {
+        let pml4 = alloc_table()?;
+        let kernel = kernel_space().pml4;
+        if kernel.as_u64() != 0 {
+            // SAFETY: both tables are valid PML4 pages.
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    table(kernel).add(ENTRIES / 2),
+                    table(pml4).add(ENTRIES / 2),
+                    ENTRIES / 2,
+                );
+            }
+        }
+        Ok(Self { pml4 })
+    }
+
+    /// The address space currently loaded in CR3.
+    ///
+    /// # Safety
+    /// The caller must not create a second owner of tables that another
+    /// `AddressSpace` is modifying.
+    pub unsafe fn active() -> Self {
+        Self { pml4: PhysAddr::new(read_cr3() & PTE_ADDR_MASK) }
+    }
+
+    /// Physical address of the PML4.
+    pub fn pml4(&self) -> PhysAddr {
+        self.pml4
+    }
+
+    /// Load this address space into CR3 on the calling CPU.
+    ///
+    /// # Safety
+    /// The code, stack and data in use must be mapped at the same addresses
+    /// in this address space.
+    pub unsafe fn activate(&self) {
+        unsafe {
+            core::arch::asm!("mov cr3, {}", in(reg) self.pml4.as_u64(), options(nostack, preserves_flags));
+        }
+    }
+
+    /// Walk down to the table holding the leaf entry for a page of `size`
+    /// at `virt`, creating intermediate tables as needed.
+    fn leaf_table(&mut self, virt: VirtAddr, size: PageSize, user: bool) -> Result<*mut u64, MapError> {
+        let mut current = table(self.pml4);
+        for level in (size.level() + 1..=4).rev() {
+            // SAFETY: `current` is a page-table page and the index is < 512.
+            unsafe {
+                let entry = current.add(index(virt, level));
+                if *entry & PTE_PRESENT == 0 {
+                    *entry = alloc_table()?.as_u64() | PTE_PRESENT | PTE_WRITABLE;
+                } else if *entry & PTE_HUGE != 0 {
+                    return Err(MapError::HugePageConflict);
+                }
+                // Ring 3 needs the user bit on every level.
+                if user {
+                    *entry |= PTE_USER;
+                }
+                current = table(PhysAddr::new(*entry & PTE_ADDR_MASK));
+            }
+        }
+        Ok(current)
+    }
+
+    /// Find the leaf entry mapping `virt`, whatever its page size.
+    fn find_leaf(&self, virt: VirtAddr) -> Option<(*mut u64, PageSize)> {
+        let mut current = table(self.pml4);
+        for level in (1..=4).rev() {
+            // SAFETY: `current` is a page-table page and the index is < 512.
+            unsafe {
+                let entry = current.add(index(virt, level));
+                if *entry & PTE_PRESENT == 0 {
+                    return None;
+                }
+                match level {
+                    1 => return Some((entry, PageSize::Size4K)),
+                    2 if *entry & PTE_HUGE != 0 => return Some((entry, PageSize::Size2M)),
+                    3 if *entry & PTE_HUGE != 0 => return Some((entry, PageSize::Size1G)),
+                    _ => current = table(PhysAddr::new(*entry & PTE_ADDR_MASK)),
+                }
+            }
+        }
+        None
+    }
+
+    /// Map the page of `size` at `virt` to `phys`.
+    pub fn map(&mut self, virt: VirtAddr, phys: PhysAddr, size: PageSize, flags: PageFlags) -> Result<(), MapError> {
+        if !virt.is_aligned(size.bytes()) || !phys.is_aligned(size.bytes()) {
+            return Err(MapError::Misaligned);
+        }
+        if size == PageSize::Size1G && !cpu::has(Feature::Page1Gb) {
+            return Err(MapError::HugePagesUnsupported);
+        }
+
+        let leaf = self.leaf_table(virt, size, flags.user)?;
+        // SAFETY: `leaf` is the table at the page's level.
+        unsafe {
+            let entry = leaf.add(index(virt, size.level()));
+            if *entry & PTE_PRESENT != 0 {
+                return Err(MapError::AlreadyMapped);
+            }
+            *entry = phys.as_u64() | flags.encode(size);
+        }
+        Ok(())
+    }
+
+    /// Map `len` bytes at `virt` to `phys`, using 1 GiB and 2 MiB pages
+    /// wherever both addresses are suitably aligned.  Both must be 4 KiB
+    /// aligned.  Pages that are already mapped are left alone.
+    pub fn map_range(&mut self, virt: VirtAddr, phys: PhysAddr, len: u64, flags: PageFlags) -> Result<(), MapError> {
+        let largest = if cpu::has(Feature::Page1Gb) { PageSize::Size1G } else { PageSize::Size2M };
+        self.map_range_up_to(virt, phys, len.next_multiple_of(PageSize::Size4K.bytes()), flags, largest)
+    }
+
+    fn map_range_up_to(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        len: u64,
+        flags: PageFlags,
+        largest: PageSize,
+    ) -> Result<(), MapError> {
+        let mut offset = 0;
+        while offset < len {
+            let (virt, phys) = (virt + offset, phys + offset);
+            let size = [PageSize::Size1G, PageSize::Size2M, PageSize::Size4K]
+                .into_iter()
+                .filter(|size| size.level() <= largest.level())
+                .find(|size| {
+                    virt.is_aligned(size.bytes())
+                        && phys.is_aligned(size.bytes())
+                        && len - offset >= size.bytes()
+                })
+                .unwrap_or(PageSize::Size4K);
+
+            match self.map(virt, phys, size, flags) {
+                Ok(()) => {}
+                // A smaller page already maps part of this one; fill in
+                // around it with smaller pages.
+                Err(MapError::AlreadyMapped) if size != PageSize::Size4K
+                    && self.translate(virt).is_none_or(|t| t.size != size) =>
+                {
+                    let smaller = if size == PageSize::Size1G { PageSize::Size2M } else { PageSize::Size4K };
+                    self.map_range_up_to(virt, phys, size.bytes(), flags, smaller)?;
+                }
+                Err(MapError::AlreadyMapped) => {}
+                // A larger page already covers this one.
+                Err(MapError::HugePageConflict) => {}
+                Err(err) => return Err(err),
+            }
+            offset += size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Remove the mapping of the page starting at `virt` and return the
+    /// frame it mapped, for the caller to free.  Page-table pages are kept.
+    pub fn unmap(&mut self, virt: VirtAddr) -> Result<(PhysAddr, PageSize), MapError> {
+        let (entry, size) = self.find_leaf(virt).ok_or(MapError::NotMapped)?;
+        if !virt.is_aligned(size.bytes()) {
+            return Err(MapError::Misaligned);
+        }
+        // SAFETY: `entry` is a present leaf entry.
+        let phys = unsafe {
+            let phys = *entry & PTE_ADDR_MASK & !(size.bytes() - 1);
+            *entry = 0;
+            phys
+        };
+        flush(virt);
+        Ok((PhysAddr::new(phys), size))
+    }
+
+    /// Change the flags of the page starting at `virt`.
+    pub fn update_flags(&mut self, virt: VirtAddr, flags: PageFlags) -> Result<(), MapError> {
+        let (entry, size) = self.find_leaf(virt).ok_or(MapError::NotMapped)?;
+        if !virt.is_aligned(size.bytes()) {
+            return Err(MapError::Misaligned);
+        }
+        // SAFETY: `entry` is a present leaf entry.
+        unsafe {
+            let phys = *entry & PTE_ADDR_MASK & !(size.bytes() - 1);
+            *entry = phys | flags.encode(size);
+        }
+        flush(virt);
+        Ok(())
+    }
+
+    /// Look up the physical address and flags `virt` maps to.
+    pub fn translate(&self, virt: VirtAddr) -> Option<Translation> {
+        let (entry, size) = self.find_leaf(virt)?;
+        // SAFETY: `entry` is a present leaf entry.
+        let entry = unsafe { *entry };
+        let base = entry & PTE_ADDR_MASK & !(size.bytes() - 1);
+        Some(Translation {
+            phys: PhysAddr::new(base + (virt.as_u64() & (size.bytes() - 1))),
+            size,
+            flags: PageFlags::decode(entry, size),
+        })
+    }
+}
+
+/// The kernel's address space.
+///
+/// Until [`init`] has run, this is a placeholder with no PML4.
+pub fn kernel_space() -> IrqSafeMutexGuard<'static, AddressSpace> {
+    KERNEL_SPACE.lock()
+}
+
+unsafe extern "C" {
+    static __limine_requests_start: u8;
+    static __limine_requests_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+}
+
+/// A PHDR of the kernel image.
+struct Segment {
+    name: &'static [u8],
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageFlags,
+}
+
+/// The kernel image's PHDRs, with the permissions they are mapped with.
+fn kernel_segments() -> [Segment; 4] {
+    let segment = |name, start: *const u8, end: *const u8, flags| Segment {
+        name,
+        start: VirtAddr::from_ptr(start).align_down(PageSize::Size4K.bytes()),
+        end: VirtAddr::from_ptr(end).align_up(PageSize::Size4K.bytes()),
+        flags,
+    };
+    [
+        segment(b".limine_requests", &raw const __limine_requests_start, &raw const __limine_requests_end, PageFlags::KERNEL_DATA),
+        segment(b".text", &raw const __text_start, &raw const __text_end, PageFlags::KERNEL_CODE),
+        segment(b".rodata", &raw const __rodata_start, &raw const __rodata_end, PageFlags::KERNEL_RODATA),
+        segment(b".data/.bss", &raw const __data_start, &raw const __data_end, PageFlags::KERNEL_DATA),
+    ]
+}
+
+/// Name of the kernel image section containing `virt`, for diagnostics.
+pub fn kernel_section(virt: VirtAddr) -> Option<&'static [u8]> {
+    kernel_segments()
+        .into_iter()
+        .find(|segment| (segment.start..segment.end).contains(&virt))
+        .map(|segment| segment.name)
+}
+
+/// Map the kernel image segment `segment` with 4 KiB pages.
+fn map_segment(space: &mut AddressSpace, segment: &Segment) -> Result<(), MapError> {
+    let phys = virt_to_phys(segment.start).ok_or(MapError::NotMapped)?;
+    let mut offset = 0;
+    while offset < segment.end - segment.start {
+        space.map(segment.start + offset, phys + offset, PageSize::Size4K, segment.flags)?;
+        offset += PageSize::Size4K.bytes();
+    }
+    Ok(())
+}
+
+/// Build the kernel's address space.
+fn build_kernel_space() -> Result<AddressSpace, MapError> {
+    let mut space = AddressSpace { pml4: alloc_table()? };
+    if let Err(error) = map_kernel_space(&mut space) {
+        // SAFETY: the tables were never loaded.
+        unsafe { free_tables(space.pml4, 4) };
+        return Err(error);
+    }
+    Ok(space)
+}
+
+/// Fill `space` with the kernel's mappings.
+fn map_kernel_space(space: &mut AddressSpace) -> Result<(), MapError> {
+    // The HHDM, over every memory-map entry but reserved and bad memory,
+    // like Limine's.  The framebuffer is write-combining, so the console's
+    // pixel stores go out in bursts instead of one bus cycle each.
+    let entries = memmap_entries().ok_or(MapError::NotMapped)?;
+    for entry in entries {
+        let flags = match entry.type_ as u32 {
+            LIMINE_MEMMAP_RESERVED | LIMINE_MEMMAP_BAD_MEMORY => continue,
+            LIMINE_MEMMAP_FRAMEBUFFER => PageFlags::KERNEL_DATA.with_cache(CacheType::WriteCombining),
+            _ => PageFlags::KERNEL_DATA,
+        };
+        let start = PhysAddr::new(entry.base).align_down(PageSize::Size4K.bytes());
+        let end = PhysAddr::new(entry.base + entry.length).align_up(PageSize::Size4K.bytes());
+        space.map_range(phys_to_virt(start), start, end - start, flags)?;
+    }
+
+    // The kernel image, one mapping per PHDR: `.text` RX, `.rodata` R,
+    // everything else RW and NX.
+    for segment in kernel_segments() {
+        map_segment(space, &segment)?;
+    }
+    Ok(())
+}
+
+/// Turn on the paging features the kernel's tables rely on, on the
+/// calling CPU: global pages, no-execute (EFER.NXE), write protection in
+/// ring 0 (CR0.WP), so W^X holds for the kernel too, and the kernel's PAT
+/// once [`init`] has switched to the kernel's tables.
+unsafe fn enable_paging_features() {
+    unsafe {
+        if cpu::has(Feature::Pge) {
+            write_cr4(read_cr4() | CR4_PGE);
+        }
+        if cpu::has(Feature::Nx) {
+            wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+            NX_ENABLED.store(true, Ordering::Relaxed);
+        }
+        write_cr0(read_cr0() | CR0_WP);
+        if PAT_ENABLED.load(Ordering::Relaxed) {
+            write_pat();
+        }
+    }
+}
+
+/// Load [`PAT_VALUE`] into the calling CPU's PAT.
+unsafe fn write_pat() {
+    // Write back the caches so no line outlives the change of its memory
+    // type.
+    unsafe {
+        wrmsr(IA32_PAT, PAT_VALUE);
+        core::arch::asm!("wbinvd", options(nostack, preserves_flags));
+    }
+}
+
+/// Build the kernel's page tables and switch to them.
+///
+/// Returns `false` if the tables could not be built, in which case the
+/// kernel stays on Limine's tables and [`kernel_space`] manages those.
+///
+/// # Safety
+/// Must be called once, on the BSP during single-threaded boot, after
+/// [`pmm::init`] and [`cpu::init`].
+pub unsafe fn init() -> bool {
+    // NXE has to be on before any entry is encoded with the NX bit.
+    unsafe { enable_paging_features() };
+
+    // SAFETY: nothing else manages Limine's tables.
+    *KERNEL_SPACE.lock() = unsafe { AddressSpace::active() };
+
+    // Encode write-combining for the kernel's PAT from here on, but leave
+    // Limine's PAT in place, which its own tables (the framebuffer among
+    // them) rely on, until the new tables replace them.
+    PAT_ENABLED.store(cpu::has(Feature::Pat), Ordering::Relaxed);
+    let Ok(space) = build_kernel_space() else {
+        PAT_ENABLED.store(false, Ordering::Relaxed);
+        return false;
+    };
+    // SAFETY: the new tables map the image, the HHDM (and with it the boot
+    // stack and Limine's responses) exactly as Limine's did, and nothing
+    // else uses the PAT entries that change.
+    unsafe {
+        if PAT_ENABLED.load(Ordering::Relaxed) {
+            write_pat();
+        }
+        space.activate();
+    }
+    *KERNEL_SPACE.lock() = space;
+    true
+}
+
+/// Switch an application processor to the kernel's page tables.
+///
+/// # Safety
+/// Must be called once per AP, after [`init`] has run on the BSP.
+pub unsafe fn init_ap() {
+    unsafe {
+        enable_paging_features();
+        KERNEL_SPACE.lock().activate();
+    }
+}
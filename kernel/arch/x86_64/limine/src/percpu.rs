@@ -0,0 +1,169 @@
+//! Per-CPU data area.
+//!
+//! Each CPU's `IA32_GS_BASE` points at its own [`PerCpu`] while it runs
+//! kernel code, so a field is one `gs:`-relative access away, from Rust
+//! through the accessors below or from assembly through the `*_OFFSET`
+//! constants.  `IA32_KERNEL_GS_BASE` holds the user-mode GS base, to be
+//! exchanged with `swapgs` on entry from and exit to ring 3.
+
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+
+use crate::msr::wrmsr;
+use crate::smp::MAX_CPUS;
+
+const IA32_GS_BASE: u32 = 0xC000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xC000_0102;
+
+/// Size of each CPU's scratch stack.
+const SCRATCH_STACK_SIZE: usize = 8 * 1024;
+
+/// Per-CPU state.  Only the owning CPU ever touches its entry, and only
+/// through `gs:`-relative accesses.
+#[repr(C)]
+pub struct PerCpu {
+    /// Address of this structure, so `gs:[0]` yields a normal pointer.
+    self_ptr: u64,
+    /// CPU number (0 for the BSP), as used by [`crate::smp`].
+    cpu_id: u64,
+    /// How many interrupt or exception handlers are active on this CPU.
+    irq_depth: u64,
+    /// Opaque pointer to the task running on this CPU; zero if none.
+    current_task: u64,
+    /// Slot where entry stubs can stash the interrupted RSP.
+    saved_rsp: u64,
+    /// Top of this CPU's scratch stack.
+    scratch_stack_top: u64,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            self_ptr: 0,
+            cpu_id: 0,
+            irq_depth: 0,
+            current_task: 0,
+            saved_rsp: 0,
+            scratch_stack_top: 0,
+        }
+    }
+}
+
+/// `gs:` offset of the interrupt nesting depth.
+pub const IRQ_DEPTH_OFFSET: usize = offset_of!(PerCpu, irq_depth);
+/// `gs:` offset of the current-task pointer.
+pub const CURRENT_TASK_OFFSET: usize = offset_of!(PerCpu, current_task);
+/// `gs:` offset of the saved-RSP slot.
+pub const SAVED_RSP_OFFSET: usize = offset_of!(PerCpu, saved_rsp);
+/// `gs:` offset of the scratch stack top.
+pub const SCRATCH_STACK_OFFSET: usize = offset_of!(PerCpu, scratch_stack_top);
+
+#[repr(C, align(16))]
+struct ScratchStack([u8; SCRATCH_STACK_SIZE]);
+
+/// Per-CPU areas and scratch stacks, wrapped in UnsafeCell so each CPU can
+/// initialise its own entry once while coming up.
+struct PerCpuCell {
+    areas: UnsafeCell<[PerCpu; MAX_CPUS]>,
+    scratch_stacks: UnsafeCell<[ScratchStack; MAX_CPUS]>,
+}
+unsafe impl Sync for PerCpuCell {}
+
+static PER_CPU: PerCpuCell = PerCpuCell {
+    areas: UnsafeCell::new([const { PerCpu::new() }; MAX_CPUS]),
+    scratch_stacks: UnsafeCell::new([const { ScratchStack([0; SCRATCH_STACK_SIZE]) }; MAX_CPUS]),
+};
+
+/// Read the `u64` at `gs:[$offset]`.
+macro_rules! gs_read {
+    ($offset:expr) => {{
+        let value: u64;
+        // SAFETY: GS points at this CPU's `PerCpu` once `init` has run.
+        unsafe {
+            core::arch::asm!(
+                "mov {}, gs:[{off}]",
+                out(reg) value,
+                off = const $offset,
+                options(nostack, preserves_flags, readonly),
+            );
+        }
+        value
+    }};
+}
+
+/// Write `$value` to the `u64` at `gs:[$offset]`.
+macro_rules! gs_write {
+    ($offset:expr, $value:expr) => {{
+        let value: u64 = $value;
+        // SAFETY: GS points at this CPU's `PerCpu` once `init` has run.
+        unsafe {
+            core::arch::asm!(
+                "mov gs:[{off}], {}",
+                in(reg) value,
+                off = const $offset,
+                options(nostack, preserves_flags),
+            );
+        }
+    }};
+}
+
+/// Set up the per-CPU area of CPU `cpu` and point the calling CPU's GS base
+/// at it.
+///
+/// # Safety
+/// Must be called once per CPU, by that CPU, after [`crate::gdt::init`]
+/// (reloading GS clears its base) and before the IDT is loaded, since the
+/// interrupt entry path relies on GS.  `cpu` must be below [`MAX_CPUS`].
+pub unsafe fn init(cpu: usize) {
+    // SAFETY: only CPU `cpu` ever touches these entries.
+    let (area, stack) = unsafe {
+        (&mut (*PER_CPU.areas.get())[cpu], &mut (*PER_CPU.scratch_stacks.get())[cpu])
+    };
+
+    let base = area as *mut PerCpu as u64;
+    *area = PerCpu {
+        self_ptr: base,
+        cpu_id: cpu as u64,
+        irq_depth: 0,
+        current_task: 0,
+        saved_rsp: 0,
+        scratch_stack_top: stack.0.as_mut_ptr() as u64 + SCRATCH_STACK_SIZE as u64,
+    };
+
+    unsafe {
+        wrmsr(IA32_GS_BASE, base);
+        wrmsr(IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/// Number of the calling CPU (0 for the BSP).
+pub fn cpu_id() -> usize {
+    gs_read!(offset_of!(PerCpu, cpu_id)) as usize
+}
+
+/// Interrupt nesting depth of the calling CPU; zero outside handlers.
+pub fn irq_depth() -> u64 {
+    gs_read!(IRQ_DEPTH_OFFSET)
+}
+
+/// Returns `true` while the calling CPU is running an interrupt or
+/// exception handler.
+pub fn in_interrupt() -> bool {
+    irq_depth() != 0
+}
+
+/// Opaque pointer to the task running on the calling CPU; zero if none.
+pub fn current_task() -> usize {
+    gs_read!(CURRENT_TASK_OFFSET) as usize
+}
+
+/// Record the task now running on the calling CPU.
+pub fn set_current_task(task: usize) {
+    gs_write!(CURRENT_TASK_OFFSET, task as u64);
+}
+
+/// Top of the calling CPU's scratch stack, for code that cannot trust the
+/// stack it is running on.
+pub fn scratch_stack_top() -> u64 {
+    gs_read!(SCRATCH_STACK_OFFSET)
+}
@@ -14,6 +14,9 @@ const ICW1_INIT: u8 = 0x10;
 const ICW1_ICW4: u8 = 0x01;
 const ICW4_8086: u8 = 0x01;
 
+/// OCW3: read the In-Service Register on the next command-port read.
+const OCW3_READ_ISR: u8 = 0x0B;
+
 /// Remap the PIC so that IRQ 0-7  → vectors 0x20-0x27
 ///                     and IRQ 8-15 → vectors 0x28-0x2F.
 ///
@@ -72,6 +75,39 @@ pub unsafe fn unmask_irq(irq: u8) {
     }
 }
 
+/// Mask (disable) a specific IRQ line (0-15).
+///
+/// # Safety
+/// Performs raw port I/O on the PIC data ports.
+pub unsafe fn mask_irq(irq: u8) {
+    unsafe {
+        if irq < 8 {
+            let mask = inb(PIC1_DATA) | (1 << irq);
+            outb(PIC1_DATA, mask);
+        } else {
+            let mask = inb(PIC2_DATA) | (1 << (irq - 8));
+            outb(PIC2_DATA, mask);
+        }
+    }
+}
+
+/// Returns `true` if `irq` is currently being serviced, i.e. its bit is set
+/// in the owning PIC's In-Service Register.  Used to detect spurious IRQs.
+///
+/// # Safety
+/// Performs raw port I/O on the PIC command ports.
+pub unsafe fn is_in_service(irq: u8) -> bool {
+    unsafe {
+        if irq < 8 {
+            outb(PIC1_CMD, OCW3_READ_ISR);
+            inb(PIC1_CMD) & (1 << irq) != 0
+        } else {
+            outb(PIC2_CMD, OCW3_READ_ISR);
+            inb(PIC2_CMD) & (1 << (irq - 8)) != 0
+        }
+    }
+}
+
 /// Mask (disable) all IRQs.
 pub unsafe fn mask_all() {
     unsafe {
@@ -0,0 +1,118 @@
+//! 8253/8254 PIT (Programmable Interval Timer) driver.
+//!
+//! Channel 0 is programmed as a rate generator on IRQ0.  Every interrupt
+//! bumps a monotonic tick counter, which backs [`uptime_ms`] and the
+//! halting [`sleep_ms`] delay, and drives the scheduler's time slices.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use crate::interrupts;
+use crate::irq::{self, IrqError};
+use crate::port::outb;
+use crate::thread;
+
+/// Input clock of the PIT in Hz.
+pub const BASE_FREQUENCY_HZ: u32 = 1_193_182;
+/// Tick rate used by [`crate::init`].
+pub const DEFAULT_FREQUENCY_HZ: u32 = 1000;
+
+const PIT_IRQ: u8 = 0;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const CMD_CHANNEL0_RATE_GENERATOR: u8 = 0x34;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Actual programmed frequency; zero until [`init`] runs.
+static FREQUENCY_HZ: AtomicU32 = AtomicU32::new(0);
+/// Set once [`init`] has the IRQ0 handler in place.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+fn pit_irq_handler() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    thread::timer_tick();
+}
+
+/// Reprogram channel 0 to fire at (approximately) `hz` interrupts per
+/// second.  The tick counter keeps running across changes.
+pub fn set_frequency(hz: u32) {
+    // A reload value of 0 means 65536, the slowest rate (~18.2 Hz).
+    let divisor = (BASE_FREQUENCY_HZ / hz.max(1)).clamp(1, 65536);
+    let reload = if divisor == 65536 { 0 } else { divisor as u16 };
+
+    // SAFETY: port I/O on the PIT command and channel 0 data ports.
+    unsafe {
+        outb(COMMAND, CMD_CHANNEL0_RATE_GENERATOR);
+        outb(CHANNEL0_DATA, reload as u8);
+        outb(CHANNEL0_DATA, (reload >> 8) as u8);
+    }
+
+    FREQUENCY_HZ.store(BASE_FREQUENCY_HZ / divisor, Ordering::Release);
+}
+
+/// Program the PIT to `hz` and start counting ticks on IRQ0.
+pub fn init(hz: u32) -> Result<(), IrqError> {
+    set_frequency(hz);
+    irq::register_irq_handler(PIT_IRQ, pit_irq_handler)?;
+    RUNNING.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Returns `true` if [`init`] succeeded, so ticks are counted (once
+/// interrupts are enabled).
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Acquire)
+}
+
+/// Current tick rate in Hz, or zero if the PIT has not been initialised.
+pub fn frequency() -> u32 {
+    FREQUENCY_HZ.load(Ordering::Acquire)
+}
+
+/// Number of timer interrupts since [`init`].
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since [`init`], at tick granularity.
+pub fn uptime_ms() -> u64 {
+    match frequency() {
+        0 => 0,
+        hz => ticks() * 1000 / hz as u64,
+    }
+}
+
+/// Halt the CPU until at least `n` more ticks have elapsed.
+///
+/// The CPU sleeps in `hlt` between interrupts instead of spinning.
+/// Interrupts are enabled while waiting and the caller's interrupt state
+/// is restored afterwards.
+pub fn sleep_ticks(n: u64) {
+    let was_enabled = interrupts::are_enabled();
+    let target = ticks() + n;
+
+    loop {
+        interrupts::disable();
+        if ticks() >= target {
+            break;
+        }
+        interrupts::enable_and_halt();
+    }
+
+    if was_enabled {
+        interrupts::enable();
+    }
+}
+
+/// Halt the CPU for at least `ms` milliseconds.
+pub fn sleep_ms(ms: u64) {
+    let hz = frequency() as u64;
+    if hz == 0 {
+        return;
+    }
+    // Round up, plus one tick because the current tick is already partly
+    // over.
+    sleep_ticks((ms * hz).div_ceil(1000) + 1);
+}
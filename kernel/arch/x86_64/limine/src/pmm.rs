@@ -0,0 +1,287 @@
+//! Physical frame allocator.
+//!
+//! A bitmap with one bit per 4 KiB frame (set = in use), seeded from the
+//! Limine memory map.  Only `USABLE` entries start out free; memory the
+//! bootloader still owns (`BOOTLOADER_RECLAIMABLE`, which includes the
+//! page tables and boot responses we run on) is tracked but stays
+//! allocated until [`release_bootloader_memory`].  A second bitmap records
+//! which frames are managed at all, so that holes (reserved, ACPI, MMIO)
+//! can never be freed into the allocator.  Both bitmaps live in the first
+//! usable region large enough to hold them and are reached through the
+//! HHDM.
+
+use crate::addr::{phys_to_virt, PhysAddr};
+use crate::sync::IrqSafeMutex;
+use crate::{
+    limine_memmap_entry, memmap_entries, LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE, LIMINE_MEMMAP_USABLE,
+};
+
+/// Size of a physical frame.
+pub const FRAME_SIZE: u64 = 4096;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Frame usage counters.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PmmStats {
+    /// Frames the allocator manages (usable plus bootloader-reclaimable).
+    pub total_frames: usize,
+    /// Frames currently free.
+    pub free_frames: usize,
+    /// Frames currently allocated, including the bitmap and memory still
+    /// owned by the bootloader.
+    pub used_frames: usize,
+    /// Bootloader-reclaimable frames not yet released.
+    pub reclaimable_frames: usize,
+}
+
+struct Pmm {
+    /// Bitmap words, one bit per frame; null before `init`.
+    bitmap: *mut u64,
+    /// Managed-frame bitmap, same layout (set = usable or
+    /// bootloader-reclaimable); null before `init`.
+    managed: *mut u64,
+    /// Number of frames covered by the bitmap.
+    frame_count: usize,
+    /// Lowest word that may contain a free bit.
+    search_hint: usize,
+    stats: PmmStats,
+}
+
+unsafe impl Send for Pmm {}
+
+static PMM: IrqSafeMutex<Pmm> = IrqSafeMutex::new(Pmm {
+    bitmap: core::ptr::null_mut(),
+    managed: core::ptr::null_mut(),
+    frame_count: 0,
+    search_hint: 0,
+    stats: PmmStats {
+        total_frames: 0,
+        free_frames: 0,
+        used_frames: 0,
+        reclaimable_frames: 0,
+    },
+});
+
+fn frame_address(frame: usize) -> PhysAddr {
+    PhysAddr::new(frame as u64 * FRAME_SIZE)
+}
+
+/// Frames spanned by `[base, base + length)`, shrunk to whole frames.
+fn frame_range(entry: &limine_memmap_entry) -> core::ops::Range<usize> {
+    let start = entry.base.div_ceil(FRAME_SIZE);
+    let end = (entry.base + entry.length) / FRAME_SIZE;
+    start as usize..end.max(start) as usize
+}
+
+impl Pmm {
+    fn words(&self) -> usize {
+        self.frame_count.div_ceil(BITS_PER_WORD)
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        // SAFETY: callers keep `frame` below `frame_count`.
+        unsafe { *self.bitmap.add(frame / BITS_PER_WORD) & (1 << (frame % BITS_PER_WORD)) != 0 }
+    }
+
+    fn is_managed(&self, frame: usize) -> bool {
+        // SAFETY: `frame` is checked against `frame_count` first.
+        frame < self.frame_count
+            && unsafe { *self.managed.add(frame / BITS_PER_WORD) & (1 << (frame % BITS_PER_WORD)) != 0 }
+    }
+
+    fn set_used(&mut self, frame: usize, used: bool) {
+        // SAFETY: callers keep `frame` below `frame_count`.
+        let word = unsafe { &mut *self.bitmap.add(frame / BITS_PER_WORD) };
+        let bit = 1 << (frame % BITS_PER_WORD);
+        if used {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+
+    /// Mark `frames` free, counting only frames that were in use.
+    fn release(&mut self, frames: core::ops::Range<usize>) {
+        for frame in frames {
+            if frame < self.frame_count && self.is_used(frame) {
+                self.set_used(frame, false);
+                self.stats.free_frames += 1;
+                self.stats.used_frames -= 1;
+                self.search_hint = self.search_hint.min(frame / BITS_PER_WORD);
+            }
+        }
+    }
+
+    fn alloc_one(&mut self) -> Option<usize> {
+        for word_index in self.search_hint..self.words() {
+            // SAFETY: `word_index` is within the bitmap.
+            let word = unsafe { *self.bitmap.add(word_index) };
+            if word == u64::MAX {
+                continue;
+            }
+            let frame = word_index * BITS_PER_WORD + word.trailing_ones() as usize;
+            if frame >= self.frame_count {
+                break;
+            }
+            self.set_used(frame, true);
+            self.stats.free_frames -= 1;
+            self.stats.used_frames += 1;
+            self.search_hint = word_index;
+            return Some(frame);
+        }
+        self.search_hint = self.words();
+        None
+    }
+
+    /// First-fit search for `count` free frames starting at a multiple of
+    /// `align_frames`.
+    fn alloc_run(&mut self, count: usize, align_frames: usize) -> Option<usize> {
+        let mut start = (self.search_hint * BITS_PER_WORD).next_multiple_of(align_frames);
+        while start + count <= self.frame_count {
+            match (start..start + count).rev().find(|&frame| self.is_used(frame)) {
+                // Restart past the used frame, at the next aligned slot.
+                Some(used) => start = (used + 1).next_multiple_of(align_frames),
+                None => {
+                    for frame in start..start + count {
+                        self.set_used(frame, true);
+                    }
+                    self.stats.free_frames -= count;
+                    self.stats.used_frames += count;
+                    return Some(start);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Build the frame bitmap from the Limine memory map.
+///
+/// Returns `false` if there is no memory map or no room for the bitmap.
+///
+/// # Safety
+/// Must be called once, during single-threaded boot.
+pub unsafe fn init() -> bool {
+    let Some(entries) = memmap_entries() else { return false };
+    let managed = |entry: &&limine_memmap_entry| {
+        entry.type_ == LIMINE_MEMMAP_USABLE as u64
+            || entry.type_ == LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE as u64
+    };
+
+    let frame_count = entries.clone().filter(managed).map(|entry| frame_range(entry).end).max().unwrap_or(0);
+    // The free bitmap followed by the managed bitmap.
+    let bitmap_bytes = (2 * frame_count.div_ceil(BITS_PER_WORD) * size_of::<u64>()) as u64;
+    let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE) as usize;
+
+    // Carve the bitmap out of the first usable region that can hold it,
+    // skipping frame 0 so that no allocation ever returns physical 0.
+    let Some(bitmap_frame) = entries.clone()
+        .filter(|entry| entry.type_ == LIMINE_MEMMAP_USABLE as u64)
+        .map(|entry| {
+            let range = frame_range(entry);
+            range.start.max(1)..range.end
+        })
+        .find(|range| range.len() >= bitmap_frames)
+        .map(|range| range.start)
+    else {
+        return false;
+    };
+
+    let mut pmm = PMM.lock();
+    pmm.bitmap = phys_to_virt(frame_address(bitmap_frame)).as_mut_ptr();
+    pmm.frame_count = frame_count;
+    // SAFETY: the bitmap region holds both bitmaps.
+    pmm.managed = unsafe { pmm.bitmap.add(pmm.words()) };
+
+    // Everything starts out used and unmanaged; then free what is usable.
+    // SAFETY: the bitmap region is usable RAM mapped by the HHDM.
+    unsafe {
+        core::ptr::write_bytes(pmm.bitmap, 0xFF, pmm.words());
+        core::ptr::write_bytes(pmm.managed, 0, pmm.words());
+    }
+
+    for entry in entries.clone().filter(managed) {
+        for frame in frame_range(entry) {
+            // SAFETY: `frame` is below `frame_count`.
+            unsafe { *pmm.managed.add(frame / BITS_PER_WORD) |= 1 << (frame % BITS_PER_WORD) };
+        }
+        let frames = frame_range(entry).len();
+        pmm.stats.total_frames += frames;
+        pmm.stats.used_frames += frames;
+        if entry.type_ == LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE as u64 {
+            pmm.stats.reclaimable_frames += frames;
+        }
+    }
+    for entry in entries.filter(|entry| entry.type_ == LIMINE_MEMMAP_USABLE as u64) {
+        let range = frame_range(entry);
+        pmm.release(range.start.max(1)..range.end);
+    }
+    for frame in bitmap_frame..bitmap_frame + bitmap_frames {
+        pmm.set_used(frame, true);
+    }
+    pmm.stats.free_frames -= bitmap_frames;
+    pmm.stats.used_frames += bitmap_frames;
+    pmm.search_hint = 0;
+    true
+}
+
+/// Hand the bootloader-reclaimable regions to the allocator.
+///
+/// # Safety
+/// Nothing may use Limine's page tables, boot stack or request responses
+/// (including the memory map) any more.
+pub unsafe fn release_bootloader_memory() {
+    let Some(entries) = memmap_entries() else { return };
+    let mut pmm = PMM.lock();
+    for entry in entries.filter(|entry| entry.type_ == LIMINE_MEMMAP_BOOTLOADER_RECLAIMABLE as u64) {
+        pmm.release(frame_range(entry));
+    }
+    pmm.stats.reclaimable_frames = 0;
+}
+
+/// Allocate one frame and return its physical address.
+pub fn alloc_frame() -> Option<PhysAddr> {
+    PMM.lock().alloc_one().map(frame_address)
+}
+
+/// Allocate `count` physically contiguous frames whose physical address is
+/// a multiple of `align` bytes (a power of two; at least one frame is
+/// implied), and return the address of the first.
+pub fn alloc_contiguous(count: usize, align: u64) -> Option<PhysAddr> {
+    if count == 0 || !align.is_power_of_two() {
+        return None;
+    }
+    let align_frames = (align / FRAME_SIZE).max(1) as usize;
+    PMM.lock().alloc_run(count, align_frames).map(frame_address)
+}
+
+/// Return the frame at `phys` to the allocator.
+///
+/// # Panics
+/// On a double free, or if `phys` is not a frame the allocator manages.
+pub fn free_frame(phys: PhysAddr) {
+    free_contiguous(phys, 1);
+}
+
+/// Return `count` frames starting at `phys`, as allocated by
+/// [`alloc_contiguous`].
+///
+/// # Panics
+/// On a double free, or if the range is not managed by the allocator.
+pub fn free_contiguous(phys: PhysAddr, count: usize) {
+    assert!(phys.is_aligned(FRAME_SIZE), "pmm: unaligned frame address");
+    let first = (phys.as_u64() / FRAME_SIZE) as usize;
+
+    let mut pmm = PMM.lock();
+    for frame in first..first + count {
+        assert!(pmm.is_managed(frame), "pmm: frame not managed");
+        assert!(pmm.is_used(frame), "pmm: double free");
+    }
+    pmm.release(first..first + count);
+}
+
+/// Current frame usage.
+pub fn stats() -> PmmStats {
+    PMM.lock().stats
+}
@@ -0,0 +1,309 @@
+//! Scheduling policies.
+//!
+//! Every run queue in [`crate::thread`] is a stack of [`Scheduler`]s, one
+//! per [`Policy`], consulted in priority order: a thread of a higher class
+//! always runs before any thread of a lower one.
+//!
+//! - [`FifoScheduler`] serves real-time threads.  The highest priority
+//!   wins and a thread keeps the CPU until it blocks, yields or is
+//!   outranked; threads of equal priority run first come, first served.
+//! - [`FairScheduler`] shares the CPU among the rest.  Each thread
+//!   accumulates virtual runtime, its CPU time scaled by a weight derived
+//!   from its nice value, and the thread with the least runs next.  A
+//!   thread with nice 0 gets about 10% more CPU than one with nice 1.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicI8, AtomicU64, AtomicU8, Ordering};
+
+use crate::thread::{self, Thread, ThreadId};
+
+/// Lowest and highest real-time priority.
+pub const MIN_RT_PRIORITY: u8 = 1;
+pub const MAX_RT_PRIORITY: u8 = 99;
+
+/// Range of nice values; lower is more CPU.
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+
+/// Weight of a nice-0 thread; virtual runtime advances in real time for it.
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weight per nice value from -20 to 19.  Each step is about 1.25x, which
+/// works out to 10% of the CPU between two otherwise equal threads.
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, // -20
+    29154, 23254, 18705, 14949, 11916, // -15
+    9548, 7620, 6100, 4904, 3906, // -10
+    3121, 2501, 1991, 1586, 1277, // -5
+    1024, 820, 655, 526, 423, // 0
+    335, 272, 215, 172, 137, // 5
+    110, 87, 70, 56, 45, // 10
+    36, 29, 23, 18, 15, // 15
+];
+
+/// How a thread is scheduled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// Real time with a priority from [`MIN_RT_PRIORITY`] to
+    /// [`MAX_RT_PRIORITY`], highest first.
+    Fifo(u8),
+    /// Fair share, weighted by the nice value.
+    Fair,
+}
+
+/// Errors returned when changing a thread's scheduling parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchedError {
+    /// No live thread has this ID.
+    NoSuchThread,
+    /// The real-time priority is out of range.
+    InvalidPriority,
+    /// The nice value is out of range.
+    InvalidNice,
+}
+
+impl Policy {
+    fn validate(self) -> Result<(), SchedError> {
+        match self {
+            Policy::Fifo(priority) if !(MIN_RT_PRIORITY..=MAX_RT_PRIORITY).contains(&priority) => {
+                Err(SchedError::InvalidPriority)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stored as 0 for [`Policy::Fair`] and the priority for
+    /// [`Policy::Fifo`].
+    fn encode(self) -> u8 {
+        match self {
+            Policy::Fifo(priority) => priority,
+            Policy::Fair => 0,
+        }
+    }
+
+    fn decode(value: u8) -> Self {
+        match value {
+            0 => Policy::Fair,
+            priority => Policy::Fifo(priority),
+        }
+    }
+}
+
+/// A thread's scheduling parameters and accounting.
+pub struct SchedState {
+    policy: AtomicU8,
+    nice: AtomicI8,
+    /// Weighted CPU time, for [`FairScheduler`].  Only changes while the
+    /// thread is not queued, since it is part of the queue key.
+    vruntime: AtomicU64,
+    cpu_time_ns: AtomicU64,
+}
+
+impl SchedState {
+    /// Fair policy, nice 0, no CPU time used.
+    pub const fn new() -> Self {
+        Self {
+            policy: AtomicU8::new(0),
+            nice: AtomicI8::new(0),
+            vruntime: AtomicU64::new(0),
+            cpu_time_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy::decode(self.policy.load(Ordering::Relaxed))
+    }
+
+    pub fn nice(&self) -> i8 {
+        self.nice.load(Ordering::Relaxed)
+    }
+
+    /// CPU time used so far, in nanoseconds.
+    pub fn cpu_time_ns(&self) -> u64 {
+        self.cpu_time_ns.load(Ordering::Relaxed)
+    }
+
+    /// Change the policy.  The thread must not be queued meanwhile.
+    pub(crate) fn set_policy(&self, policy: Policy) -> Result<(), SchedError> {
+        policy.validate()?;
+        self.policy.store(policy.encode(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn set_nice(&self, nice: i8) -> Result<(), SchedError> {
+        if !(MIN_NICE..=MAX_NICE).contains(&nice) {
+            return Err(SchedError::InvalidNice);
+        }
+        self.nice.store(nice, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(crate) fn add_cpu_time(&self, ns: u64) {
+        self.cpu_time_ns.fetch_add(ns, Ordering::Relaxed);
+    }
+
+    fn weight(&self) -> u64 {
+        NICE_WEIGHTS[(self.nice() - MIN_NICE) as usize]
+    }
+
+    fn vruntime(&self) -> u64 {
+        self.vruntime.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for SchedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One scheduling class of a CPU's run queue.  Methods are called with
+/// the run queue locked.
+pub trait Scheduler: Send {
+    /// Queue `thread`, which is ready to run.
+    fn enqueue(&mut self, thread: Arc<Thread>);
+
+    /// Take the queued thread that should run next.
+    fn pick_next(&mut self) -> Option<Arc<Thread>>;
+
+    /// Take `thread` out of the queue.  Returns `false` if it was not
+    /// queued.
+    fn remove(&mut self, thread: &Thread) -> bool;
+
+    /// Returns `true` if no thread is queued.
+    fn is_empty(&self) -> bool;
+
+    /// Charge `ns` nanoseconds of CPU time to `current`, a running thread
+    /// of this class.
+    fn charge(&mut self, current: &Thread, ns: u64);
+
+    /// How long `current` may run before queued threads of this class get
+    /// their turn; `None` if it may run until it gives up the CPU.
+    fn time_slice_ns(&self, current: &Thread) -> Option<u64>;
+
+    /// Returns `true` if a queued thread should take the CPU from
+    /// `current`, a running thread of this class, without waiting for its
+    /// time slice to run out.
+    fn should_preempt(&self, current: &Thread) -> bool;
+}
+
+/// Real-time threads, one first-in first-out queue per priority.
+pub struct FifoScheduler {
+    levels: [VecDeque<Arc<Thread>>; MAX_RT_PRIORITY as usize + 1],
+}
+
+impl FifoScheduler {
+    pub const fn new() -> Self {
+        Self { levels: [const { VecDeque::new() }; MAX_RT_PRIORITY as usize + 1] }
+    }
+
+    fn priority(thread: &Thread) -> usize {
+        match thread.sched().policy() {
+            Policy::Fifo(priority) => priority as usize,
+            Policy::Fair => 0,
+        }
+    }
+
+    /// Priority of the highest queued thread.
+    fn highest(&self) -> Option<usize> {
+        self.levels.iter().rposition(|level| !level.is_empty())
+    }
+}
+
+impl Default for FifoScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for FifoScheduler {
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        self.levels[Self::priority(&thread)].push_back(thread);
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let highest = self.highest()?;
+        self.levels[highest].pop_front()
+    }
+
+    fn remove(&mut self, thread: &Thread) -> bool {
+        let level = &mut self.levels[Self::priority(thread)];
+        match level.iter().position(|queued| core::ptr::eq(Arc::as_ptr(queued), thread)) {
+            Some(index) => level.remove(index).is_some(),
+            None => false,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.highest().is_none()
+    }
+
+    fn charge(&mut self, _current: &Thread, _ns: u64) {}
+
+    fn time_slice_ns(&self, _current: &Thread) -> Option<u64> {
+        None
+    }
+
+    fn should_preempt(&self, current: &Thread) -> bool {
+        self.highest().is_some_and(|highest| highest > Self::priority(current))
+    }
+}
+
+/// Fair-share threads, ordered by virtual runtime.
+pub struct FairScheduler {
+    queue: BTreeMap<(u64, ThreadId), Arc<Thread>>,
+    /// Never decreases; threads joining the queue start no further behind
+    /// than one time slice, so a thread that slept for a long time cannot
+    /// claim all of that time back at once.
+    min_vruntime: u64,
+}
+
+impl FairScheduler {
+    pub const fn new() -> Self {
+        Self { queue: BTreeMap::new(), min_vruntime: 0 }
+    }
+}
+
+impl Default for FairScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler for FairScheduler {
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        let state = thread.sched();
+        let floor = self.min_vruntime.saturating_sub(thread::time_slice_ms() * 1_000_000);
+        let vruntime = state.vruntime().max(floor);
+        state.vruntime.store(vruntime, Ordering::Relaxed);
+        self.queue.insert((vruntime, thread.id()), thread);
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        let ((vruntime, _), thread) = self.queue.pop_first()?;
+        self.min_vruntime = self.min_vruntime.max(vruntime);
+        Some(thread)
+    }
+
+    fn remove(&mut self, thread: &Thread) -> bool {
+        self.queue.remove(&(thread.sched().vruntime(), thread.id())).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn charge(&mut self, current: &Thread, ns: u64) {
+        let state = current.sched();
+        state.vruntime.fetch_add(ns * NICE_0_WEIGHT / state.weight(), Ordering::Relaxed);
+    }
+
+    fn time_slice_ns(&self, _current: &Thread) -> Option<u64> {
+        Some(thread::time_slice_ms() * 1_000_000)
+    }
+
+    fn should_preempt(&self, _current: &Thread) -> bool {
+        false
+    }
+}
@@ -0,0 +1,225 @@
+//! Slab allocator for small kernel objects.
+//!
+//! Allocations of up to [`MAX_OBJECT_SIZE`] bytes are served from one of
+//! several object caches, one per power-of-two size class.  A cache carves
+//! whole frames (reached through the HHDM) into equal objects and keeps
+//! the free ones on an intrusive list, the depot.  In front of the depot
+//! every CPU has a magazine of recently freed objects per cache, so the
+//! common alloc/free pair touches neither a lock nor another CPU's cache
+//! lines.  [`crate::heap`]'s global allocator routes small requests here.
+
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::addr::phys_to_virt;
+use crate::interrupts;
+use crate::percpu;
+use crate::pmm::{self, FRAME_SIZE};
+use crate::smp::MAX_CPUS;
+use crate::sync::IrqSafeMutex;
+
+const MIN_OBJECT_SIZE: usize = 16;
+/// Largest size class; bigger requests go to the heap.
+pub const MAX_OBJECT_SIZE: usize = 2048;
+/// Number of size classes, 16 to 2048 bytes.
+pub const CLASS_COUNT: usize = (MAX_OBJECT_SIZE / MIN_OBJECT_SIZE).trailing_zeros() as usize + 1;
+
+/// Objects a magazine holds; refills and flushes move half of that.
+const MAGAZINE_SIZE: usize = 32;
+
+/// Usage counters of one object cache.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlabStats {
+    pub object_size: usize,
+    /// Frames the cache has carved up.
+    pub slabs: usize,
+    /// Objects currently allocated.
+    pub objects_in_use: usize,
+    /// Free objects, in the depot or a magazine.
+    pub objects_free: usize,
+}
+
+/// A free object, linked through its first word.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+struct Depot {
+    free: *mut FreeObject,
+}
+
+unsafe impl Send for Depot {}
+
+struct Cache {
+    object_size: usize,
+    depot: IrqSafeMutex<Depot>,
+    slabs: AtomicUsize,
+    in_use: AtomicUsize,
+}
+
+struct Magazine {
+    objects: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+/// Every CPU's magazines, one per cache.  Only the owning CPU touches its
+/// row, with interrupts disabled.
+struct MagazineCell(UnsafeCell<[[Magazine; CLASS_COUNT]; MAX_CPUS]>);
+unsafe impl Sync for MagazineCell {}
+
+static CACHES: [Cache; CLASS_COUNT] = {
+    let mut caches = [const {
+        Cache {
+            object_size: 0,
+            depot: IrqSafeMutex::new(Depot { free: ptr::null_mut() }),
+            slabs: AtomicUsize::new(0),
+            in_use: AtomicUsize::new(0),
+        }
+    }; CLASS_COUNT];
+    let mut class = 0;
+    while class < CLASS_COUNT {
+        caches[class].object_size = MIN_OBJECT_SIZE << class;
+        class += 1;
+    }
+    caches
+};
+
+static MAGAZINES: MagazineCell = MagazineCell(UnsafeCell::new(
+    [const { [const { Magazine { objects: [ptr::null_mut(); MAGAZINE_SIZE], count: 0 } }; CLASS_COUNT] }; MAX_CPUS],
+));
+
+/// The size class serving `layout`, if it is small enough for a slab.
+/// Objects are aligned to their size, so this covers alignment too.
+fn class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_OBJECT_SIZE).next_power_of_two();
+    (size <= MAX_OBJECT_SIZE).then(|| (size / MIN_OBJECT_SIZE).trailing_zeros() as usize)
+}
+
+/// The calling CPU's magazine for `class`.
+///
+/// # Safety
+/// Interrupts must be disabled for as long as the reference is used.
+unsafe fn magazine(class: usize) -> &'static mut Magazine {
+    unsafe { &mut (*MAGAZINES.0.get())[percpu::cpu_id()][class] }
+}
+
+impl Depot {
+    fn push(&mut self, object: *mut u8) {
+        let object = object as *mut FreeObject;
+        // SAFETY: free objects are unused memory at least a word long.
+        unsafe { object.write(FreeObject { next: self.free }) };
+        self.free = object;
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.free.is_null() {
+            return None;
+        }
+        let object = self.free;
+        // SAFETY: `object` is on the free list.
+        self.free = unsafe { (*object).next };
+        Some(object as *mut u8)
+    }
+}
+
+impl Cache {
+    /// Carve a fresh frame into objects and add them to the depot.
+    fn grow(&self, depot: &mut Depot) -> bool {
+        let Some(frame) = pmm::alloc_frame() else { return false };
+        let base = phys_to_virt(frame).as_mut_ptr::<u8>();
+        for index in (0..FRAME_SIZE as usize / self.object_size).rev() {
+            // SAFETY: the frame is ours and mapped by the HHDM.
+            depot.push(unsafe { base.add(index * self.object_size) });
+        }
+        self.slabs.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Move up to half a magazine from the depot into `magazine`.
+    fn refill(&self, magazine: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        while magazine.count < MAGAZINE_SIZE / 2 {
+            let Some(object) = depot.pop().or_else(|| {
+                if self.grow(&mut depot) { depot.pop() } else { None }
+            }) else {
+                break;
+            };
+            magazine.objects[magazine.count] = object;
+            magazine.count += 1;
+        }
+    }
+
+    /// Move half of a full `magazine` back to the depot.
+    fn flush(&self, magazine: &mut Magazine) {
+        let mut depot = self.depot.lock();
+        while magazine.count > MAGAZINE_SIZE / 2 {
+            magazine.count -= 1;
+            depot.push(magazine.objects[magazine.count]);
+        }
+    }
+
+    fn alloc(&self, class: usize) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            // SAFETY: interrupts are off.
+            let magazine = unsafe { magazine(class) };
+            if magazine.count == 0 {
+                self.refill(magazine);
+                if magazine.count == 0 {
+                    return ptr::null_mut();
+                }
+            }
+            magazine.count -= 1;
+            self.in_use.fetch_add(1, Ordering::Relaxed);
+            magazine.objects[magazine.count]
+        })
+    }
+
+    fn free(&self, class: usize, object: *mut u8) {
+        interrupts::without_interrupts(|| {
+            // SAFETY: interrupts are off.
+            let magazine = unsafe { magazine(class) };
+            if magazine.count == MAGAZINE_SIZE {
+                self.flush(magazine);
+            }
+            magazine.objects[magazine.count] = object;
+            magazine.count += 1;
+            self.in_use.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+}
+
+/// Allocate an object for `layout`, or `None` if it is too large for the
+/// slab caches.  `Some(null)` means the cache is out of memory.
+pub fn alloc(layout: Layout) -> Option<*mut u8> {
+    let class = class_of(layout)?;
+    Some(CACHES[class].alloc(class))
+}
+
+/// Free `ptr`, allocated by [`alloc`] with the same `layout`.  Returns
+/// `false` if `layout` does not belong to the slab caches.
+///
+/// # Safety
+/// `ptr` must have come from [`alloc`] with `layout` and not been freed.
+pub unsafe fn free(ptr: *mut u8, layout: Layout) -> bool {
+    let Some(class) = class_of(layout) else { return false };
+    CACHES[class].free(class, ptr);
+    true
+}
+
+/// Usage of every size class, smallest first.
+pub fn stats() -> [SlabStats; CLASS_COUNT] {
+    core::array::from_fn(|class| {
+        let cache = &CACHES[class];
+        let slabs = cache.slabs.load(Ordering::Relaxed);
+        let in_use = cache.in_use.load(Ordering::Relaxed);
+        let capacity = slabs * (FRAME_SIZE as usize / cache.object_size);
+        SlabStats {
+            object_size: cache.object_size,
+            slabs,
+            objects_in_use: in_use,
+            objects_free: capacity.saturating_sub(in_use),
+        }
+    })
+}
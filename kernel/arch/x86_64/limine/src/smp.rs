@@ -0,0 +1,240 @@
+//! Application processor bring-up.
+//!
+//! Limine parks every AP and lists it in the MP response.  [`init`] gives
+//! each AP its own guarded [`KernelStack`] and points it at `_ap_entry`;
+//! the AP then loads its own GDT/TSS, the shared IDT and its local APIC,
+//! starts its local APIC timer for the scheduler tick, and waits in an
+//! idle loop for work posted with [`run_on`].  A wake-up IPI on
+//! [`WAKE_VECTOR`] gets it out of `hlt`.
+//!
+//! CPU 0 is always the BSP; APs are numbered from 1 in the order Limine
+//! reports them.  APs are only started when the local APIC is in use,
+//! since waking them needs IPIs.
+
+use alloc::format;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+use crate::apic;
+use crate::cpu;
+use crate::gdt;
+use crate::interrupts;
+use crate::limine_mp_info;
+use crate::paging;
+use crate::percpu;
+use crate::stack::KernelStack;
+use crate::thread;
+use crate::uaccess;
+
+/// Maximum number of CPUs the kernel brings up, BSP included.
+pub const MAX_CPUS: usize = 16;
+
+/// Vector of the IPI that wakes an idle AP to look for work.
+pub const WAKE_VECTOR: u8 = 0xF0;
+
+/// Size of each AP's kernel stack.
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// Work handed to an idle AP.
+pub type Work = fn();
+
+/// Errors returned by [`run_on`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmpError {
+    /// No such AP is online (the BSP never runs posted work).
+    InvalidCpu,
+    /// The AP has not finished its previous work yet.
+    Busy,
+}
+
+/// Initial stack pointer of each AP, read by `_ap_entry`.
+static AP_STACK_TOPS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+
+/// Number of CPUs that have finished coming up, BSP included.
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// Local APIC ID of each CPU, indexed by CPU number.
+static LAPIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(0) }; MAX_CPUS];
+/// Pending work per CPU, stored as a `fn()` address; zero means idle.
+static WORK: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+// AP entry point.  Limine jumps here with RDI pointing at the AP's
+// `limine_mp_info`, whose `extra_argument` holds the CPU number.  Switch to
+// that CPU's stack, enable SSE as `kernel_main` does for the BSP, and enter
+// Rust.
+core::arch::global_asm!(
+    ".global _ap_entry",
+    "_ap_entry:",
+    "mov rax, [rdi + 24]",
+    "lea rcx, [rip + {stack_tops}]",
+    "mov rsp, [rcx + rax * 8]",
+    "xor ebp, ebp",
+    // Clear CR0.EM, set CR0.MP; set CR4.OSFXSR and CR4.OSXMMEXCPT.
+    "mov rax, cr0",
+    "and eax, 0xFFFFFFFB",
+    "or  eax, 0x2",
+    "mov cr0, rax",
+    "mov rax, cr4",
+    "or  eax, 0x600",
+    "mov cr4, rax",
+    "call {ap_main}",
+    "cli",
+    "2: hlt",
+    "jmp 2b",
+    stack_tops = sym AP_STACK_TOPS,
+    ap_main = sym ap_main,
+);
+
+// Wake-up IPI stub: enter the common path like an IRQ would.
+core::arch::global_asm!(
+    ".global _smp_wake_stub",
+    "_smp_wake_stub:",
+    "push 0",
+    "push {vector}",
+    "jmp _isr_common",
+    vector = const WAKE_VECTOR,
+);
+
+unsafe extern "C" {
+    fn _ap_entry(info: *mut limine_mp_info);
+    fn _smp_wake_stub();
+}
+
+/// Address of the handler for [`WAKE_VECTOR`].
+pub fn wake_stub_address() -> u64 {
+    _smp_wake_stub as *const () as u64
+}
+
+/// Called from the common interrupt path for [`WAKE_VECTOR`].  The idle
+/// loop does the actual work; the IPI only has to be acknowledged.
+pub fn handle_wake() {
+    // SAFETY: wake IPIs are only sent once the local APIC is enabled.
+    unsafe { apic::send_eoi(0); }
+}
+
+/// Rust side of `_ap_entry`, running on the AP's own stack.
+extern "C" fn ap_main(info: &limine_mp_info) -> ! {
+    let cpu = info.extra_argument as usize;
+
+    // SAFETY: runs once per AP, which owns CPU number `cpu`.
+    unsafe {
+        paging::init_ap();
+        gdt::init(cpu);
+        percpu::init(cpu);
+        // XCR0 and SMAP first: the interrupt path relies on the BSP's
+        // choice of `xsave` and `clac`.
+        cpu::init_ap();
+        uaccess::init();
+        crate::load_idt();
+        apic::init_ap();
+        thread::init(&format!("cpu{cpu} idle"), None);
+        apic::start_timer();
+    }
+
+    ONLINE.fetch_add(1, Ordering::Release);
+    idle_loop(cpu)
+}
+
+/// Run posted work, halting whenever there is none.
+fn idle_loop(cpu: usize) -> ! {
+    loop {
+        // Check with interrupts off, so a wake IPI arriving right after the
+        // check still ends the `hlt` below.
+        interrupts::disable();
+        let work = WORK[cpu].load(Ordering::Acquire);
+        if work == 0 {
+            interrupts::enable_and_halt();
+            continue;
+        }
+
+        interrupts::enable();
+        // SAFETY: only `fn()` addresses are ever stored in `WORK`.
+        let work = unsafe { core::mem::transmute::<usize, Work>(work) };
+        work();
+        WORK[cpu].store(0, Ordering::Release);
+    }
+}
+
+/// Start every AP Limine reports (up to [`MAX_CPUS`]) and wait until they
+/// are all idle.
+///
+/// # Safety
+/// Must be called once, on the BSP, after [`gdt::init`], the IDT and
+/// [`apic::init`].
+pub unsafe fn init() {
+    if !apic::is_enabled() {
+        return;
+    }
+    LAPIC_IDS[0].store(apic::lapic_id(), Ordering::Relaxed);
+
+    let Some(response) = crate::mp_response() else { return };
+
+    // SAFETY: Limine provides `cpu_count` valid entries.
+    let cpus = unsafe { core::slice::from_raw_parts(response.cpus, response.cpu_count as usize) };
+    let mut next = 1;
+    for &info in cpus {
+        if next == MAX_CPUS {
+            break;
+        }
+        // SAFETY: each entry points at a valid `limine_mp_info`.
+        let info = unsafe { &mut *info };
+        if info.lapic_id == response.bsp_lapic_id {
+            continue;
+        }
+
+        // An AP's stack lives as long as the AP runs, i.e. forever.
+        let Some(stack) = KernelStack::new(AP_STACK_SIZE, &format!("cpu{next} idle")) else { break };
+        AP_STACK_TOPS[next].store(stack.top().as_u64(), Ordering::Release);
+        core::mem::forget(stack);
+
+        LAPIC_IDS[next].store(info.lapic_id, Ordering::Relaxed);
+        // SAFETY: the AP polls `goto_address`, so it must be written last
+        // and in a single store.
+        unsafe {
+            core::ptr::write_volatile(&mut info.extra_argument, next as u64);
+            core::ptr::write_volatile(&mut info.goto_address, Some(_ap_entry));
+        }
+        next += 1;
+    }
+
+    while ONLINE.load(Ordering::Acquire) < next {
+        core::hint::spin_loop();
+    }
+}
+
+/// Number of CPUs online, BSP included.
+pub fn cpu_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// Number of the calling CPU (0 for the BSP).
+pub fn current_cpu() -> usize {
+    percpu::cpu_id()
+}
+
+/// Post `work` to idle AP `cpu` and wake it.
+pub fn run_on(cpu: usize, work: Work) -> Result<(), SmpError> {
+    if cpu == 0 || cpu >= cpu_count() {
+        return Err(SmpError::InvalidCpu);
+    }
+    WORK[cpu]
+        .compare_exchange(0, work as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| SmpError::Busy)?;
+
+    // SAFETY: APs are only online when the local APIC is in use, and they
+    // all handle WAKE_VECTOR.
+    unsafe { apic::send_ipi(LAPIC_IDS[cpu].load(Ordering::Relaxed), WAKE_VECTOR); }
+    Ok(())
+}
+
+/// Send `cpu` a wake-up IPI, to get it out of `hlt` so it notices new
+/// work or a newly runnable thread.
+pub fn kick(cpu: usize) {
+    if cpu < cpu_count() && cpu != current_cpu() {
+        // SAFETY: as in `run_on`.
+        unsafe { apic::send_ipi(LAPIC_IDS[cpu].load(Ordering::Relaxed), WAKE_VECTOR); }
+    }
+}
+
+/// Returns `true` if `cpu` has no pending or running work.
+pub fn is_idle(cpu: usize) -> bool {
+    WORK.get(cpu).is_some_and(|work| work.load(Ordering::Acquire) == 0)
+}
@@ -0,0 +1,148 @@
+//! Kernel stacks with guard pages.
+//!
+//! Stacks live in a virtual region of their own, divided into fixed-size
+//! slots.  A stack occupies the top of its slot; the rest of the slot,
+//! including at least one page right below the stack, stays unmapped, so
+//! running off the bottom faults instead of silently corrupting whatever
+//! lies below.  The #DF and #PF handlers ask [`find_overflow`] whether a
+//! faulting address hit such a guard.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::addr::VirtAddr;
+use crate::paging::{kernel_space, AddressSpace, PageFlags, PageSize};
+use crate::pmm;
+use crate::sync::IrqSafeMutex;
+
+/// Start of the stack region: PML4 slot 509, below the heap.
+const STACK_REGION_START: u64 = 0xFFFF_FE80_0000_0000;
+const STACK_REGION_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+/// Size of each slot, guard included.
+const SLOT_SIZE: u64 = 256 * 1024;
+const PAGE_SIZE: u64 = PageSize::Size4K.bytes();
+
+/// Largest stack a slot can hold.
+pub const MAX_STACK_SIZE: usize = (SLOT_SIZE - PAGE_SIZE) as usize;
+
+struct Slot {
+    /// Lowest mapped address of the stack.
+    bottom: VirtAddr,
+    /// What runs on the stack, for overflow reports.
+    owner: String,
+}
+
+struct StackArea {
+    /// Slot states, indexed by slot number; `None` is free.
+    slots: Vec<Option<Slot>>,
+}
+
+static STACKS: IrqSafeMutex<StackArea> = IrqSafeMutex::new(StackArea { slots: Vec::new() });
+
+fn slot_base(slot: usize) -> VirtAddr {
+    VirtAddr::new(STACK_REGION_START + slot as u64 * SLOT_SIZE)
+}
+
+/// A kernel stack with an unmapped guard page below it.  Dropping it
+/// unmaps the stack and frees its frames.
+pub struct KernelStack {
+    slot: usize,
+    bottom: VirtAddr,
+}
+
+impl KernelStack {
+    /// Allocate a stack of `size` bytes (rounded up to whole pages, at most
+    /// [`MAX_STACK_SIZE`]), owned by `owner`.
+    pub fn new(size: usize, owner: &str) -> Option<Self> {
+        let size = (size as u64).next_multiple_of(PAGE_SIZE);
+        if size == 0 || size > MAX_STACK_SIZE as u64 {
+            return None;
+        }
+
+        let mut area = STACKS.lock();
+        let slot = match area.slots.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if (area.slots.len() as u64 + 1) * SLOT_SIZE <= STACK_REGION_SIZE => {
+                area.slots.push(None);
+                area.slots.len() - 1
+            }
+            None => return None,
+        };
+
+        let top = slot_base(slot) + SLOT_SIZE;
+        let bottom = top - size;
+        let mut space = kernel_space();
+        let mut page = bottom;
+        while page < top {
+            let mapped = pmm::alloc_frame().map(|frame| {
+                let result = space.map(page, frame, PageSize::Size4K, PageFlags::KERNEL_DATA);
+                if result.is_err() {
+                    pmm::free_frame(frame);
+                }
+                result.is_ok()
+            });
+            if mapped != Some(true) {
+                unmap(&mut space, bottom, page);
+                return None;
+            }
+            page += PAGE_SIZE;
+        }
+        drop(space);
+
+        area.slots[slot] = Some(Slot { bottom, owner: String::from(owner) });
+        Some(Self { slot, bottom })
+    }
+
+    /// Initial stack pointer: the address just past the top.
+    pub fn top(&self) -> VirtAddr {
+        slot_base(self.slot) + SLOT_SIZE
+    }
+
+    /// Lowest usable address.
+    pub fn bottom(&self) -> VirtAddr {
+        self.bottom
+    }
+
+    /// Name what runs on the stack, for overflow reports.
+    pub fn set_owner(&self, owner: &str) {
+        if let Some(slot) = &mut STACKS.lock().slots[self.slot] {
+            slot.owner = String::from(owner);
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut area = STACKS.lock();
+        unmap(&mut kernel_space(), self.bottom, self.top());
+        area.slots[self.slot] = None;
+    }
+}
+
+/// Unmap `start..end` and free the frames behind it.
+fn unmap(space: &mut AddressSpace, start: VirtAddr, end: VirtAddr) {
+    let mut page = start;
+    while page < end {
+        if let Ok((frame, _)) = space.unmap(page) {
+            pmm::free_frame(frame);
+        }
+        page += PAGE_SIZE;
+    }
+}
+
+/// If `addr` lies in the guard area below a kernel stack, call `report`
+/// with the stack's owner.
+///
+/// Meant for the exception handlers: gives up rather than spin if the stack
+/// registry is locked.
+pub fn find_overflow<R>(addr: VirtAddr, report: impl FnOnce(&str) -> R) -> Option<R> {
+    let offset = addr.as_u64().checked_sub(STACK_REGION_START)?;
+    if offset >= STACK_REGION_SIZE {
+        return None;
+    }
+    let index = (offset / SLOT_SIZE) as usize;
+
+    let area = STACKS.try_lock()?;
+    let slot = area.slots.get(index)?.as_ref()?;
+    (addr < slot.bottom).then(|| report(&slot.owner))
+}
@@ -0,0 +1,105 @@
+//! Locks for state shared with interrupt handlers.
+//!
+//! A plain spinlock deadlocks as soon as an interrupt handler tries to take
+//! a lock that the code it interrupted already holds.  [`IrqSafeMutex`]
+//! disables interrupts on the local CPU for as long as the lock is held and
+//! restores the previous interrupt state when the guard is dropped, so any
+//! state touched from both IRQ handlers and normal code (the terminal, the
+//! keyboard buffer, driver state) belongs behind one.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use crate::interrupts;
+
+/// A spinlock that keeps interrupts disabled while it is held.
+pub struct IrqSafeMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+/// RAII guard for [`IrqSafeMutex`].  Dropping it releases the lock, then
+/// re-enables interrupts if they were enabled when the lock was taken.
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Create a new, unlocked mutex.
+    pub const fn new(value: T) -> Self {
+        Self { inner: spin::Mutex::new(value) }
+    }
+
+    /// Disable interrupts and spin until the lock is acquired.
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+
+    /// Acquire the lock if it is free.  Interrupts are left untouched if
+    /// it is not.
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_were_enabled,
+            }),
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if the lock is currently held.
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Mutable access without locking; the borrow proves exclusivity.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.inner.get_mut()
+    }
+
+    /// Release the lock without a guard, e.g. from a fatal exception
+    /// handler that must print no matter what.
+    ///
+    /// # Safety
+    /// The current holder must never touch the data again.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the inner guard is dropped exactly once, here, and before
+        // interrupts can come back on.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
@@ -0,0 +1,762 @@
+//! Kernel threads.
+//!
+//! A [`Thread`] is a kernel stack, a saved stack pointer and a saved
+//! x87/SSE/AVX state.  `_switch_to` pushes the callee-saved registers on
+//! the old thread's stack, saves its extended state with `xsave` (or
+//! `fxsave` without XSAVE), loads the new thread's stack pointer and state
+//! and pops its registers, so the switch looks like an ordinary function
+//! call to both sides.
+//!
+//! Every CPU has its own run queue, made of the scheduling classes in
+//! [`crate::sched`]; [`spawn`] queues the new thread on the calling CPU.
+//! [`init`] adopts the code already running on a CPU as that CPU's first
+//! thread, on the stack it was started on, and starts an idle thread that
+//! halts whenever nothing else is ready.
+//!
+//! A thread blocks with [`prepare_to_wait`] and [`block`] (or
+//! [`block_until`], to give up at a deadline) and is made ready again by
+//! [`wake`]; [`crate::wait::WaitQueue`] wraps them.
+//!
+//! Threads give up the CPU with [`yield_now`], or are preempted: the timer
+//! interrupt charges CPU time to the running thread, and once its time
+//! slice is used up (and another thread is waiting) or a thread of higher
+//! priority is ready, the interrupt switches threads on its way out.  A
+//! preempted thread resumes inside that interrupt and returns from it as
+//! usual.  The BSP's timer is the PIT and the APs' is their local APIC
+//! timer; a thread woken for an AP from another CPU takes over on the
+//! wake-up IPI if it outranks the running one, without waiting for a tick.
+
+use alloc::alloc::{alloc_zeroed, dealloc};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+use crate::cpu;
+use crate::interrupts;
+use crate::percpu;
+use crate::pit;
+use crate::sched::{FairScheduler, FifoScheduler, Policy, SchedError, SchedState, Scheduler};
+use crate::smp::{self, MAX_CPUS};
+use crate::stack::KernelStack;
+use crate::sync::IrqSafeMutex;
+use crate::tsc;
+
+/// Stack size of spawned threads.
+pub const THREAD_STACK_SIZE: usize = 64 * 1024;
+/// Time slice threads start out with.
+pub const DEFAULT_TIME_SLICE_MS: u64 = 10;
+
+/// Size of an `fxsave` area, used when the CPU has no XSAVE.
+const FXSAVE_SIZE: usize = 512;
+/// `xsave` needs 64-byte alignment, `fxsave` 16.
+const FPU_ALIGN: usize = 64;
+/// Offsets and reset values of the x87 control word and MXCSR in the
+/// legacy area; a zeroed area would unmask every FP exception.
+const FCW_OFFSET: usize = 0;
+const FCW_DEFAULT: u16 = 0x037F;
+const MXCSR_OFFSET: usize = 24;
+const MXCSR_DEFAULT: u32 = 0x1F80;
+
+/// Whether `_switch_to` uses `xsave`/`xrstor` rather than
+/// `fxsave`/`fxrstor`.
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+// Thread states.  Changed only with the thread's run queue locked.
+/// Running or queued to run.
+const RUNNABLE: u8 = 0;
+/// Running, but about to [`block`]; a [`wake`] meanwhile cancels that.
+const WAITING: u8 = 1;
+/// Off the run queue until [`wake`] is called.
+const BLOCKED: u8 = 2;
+static TIME_SLICE_MS: AtomicU64 = AtomicU64::new(DEFAULT_TIME_SLICE_MS);
+
+/// Identifies a thread for as long as the kernel runs; IDs are never
+/// reused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Saved state of a thread that is not running, as `_switch_to` sees it.
+#[repr(C)]
+struct Context {
+    /// Stack pointer, with the callee-saved registers and the return
+    /// address on top.
+    rsp: u64,
+    /// The thread's XSAVE or FXSAVE area.
+    fpu: *mut u8,
+    /// Interrupt nesting depth: non-zero for a thread preempted inside an
+    /// interrupt handler.
+    irq_depth: u64,
+}
+
+/// A thread's extended-state save area.
+struct FpuState {
+    area: *mut u8,
+    layout: Layout,
+}
+
+impl FpuState {
+    /// An area holding the reset state: registers zero, exceptions masked.
+    fn new() -> Option<Self> {
+        let size = if USE_XSAVE.load(Ordering::Relaxed) {
+            cpu::info().xsave_size as usize
+        } else {
+            FXSAVE_SIZE
+        };
+        let layout = Layout::from_size_align(size, FPU_ALIGN).ok()?;
+        // SAFETY: `layout` has a non-zero size.
+        let area = unsafe { alloc_zeroed(layout) };
+        if area.is_null() {
+            return None;
+        }
+        // SAFETY: both fields lie in the legacy area at the start of the
+        // allocation.  With a zeroed XSAVE header `xrstor` puts the x87
+        // and SSE registers in their init state but still loads MXCSR.
+        unsafe {
+            area.add(FCW_OFFSET).cast::<u16>().write(FCW_DEFAULT);
+            area.add(MXCSR_OFFSET).cast::<u32>().write(MXCSR_DEFAULT);
+        }
+        Some(Self { area, layout })
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // SAFETY: allocated in `new` with this layout.
+        unsafe { dealloc(self.area, self.layout) };
+    }
+}
+
+/// Every thread, for [`threads`] and lookups by ID.  Entries of exited
+/// threads are pruned as they are dropped, which takes the lock: nothing
+/// may drop an upgraded reference while holding it.
+static THREADS: IrqSafeMutex<Vec<(ThreadId, Weak<Thread>)>> = IrqSafeMutex::new(Vec::new());
+
+/// Threads in [`block_until`], with their deadlines in
+/// [`tsc::monotonic_ns`] time.
+static TIMEOUTS: IrqSafeMutex<Vec<(u64, Arc<Thread>)>> = IrqSafeMutex::new(Vec::new());
+
+/// A kernel thread.
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    /// The CPU whose run queue the thread belongs to.
+    cpu: usize,
+    sched: SchedState,
+    /// [`RUNNABLE`], [`WAITING`] or [`BLOCKED`].
+    state: AtomicU8,
+    context: UnsafeCell<Context>,
+    _fpu: FpuState,
+    /// `None` for a thread adopted by [`init`] on a stack it does not
+    /// own.
+    _stack: Option<KernelStack>,
+}
+
+// SAFETY: `context` is only touched by `_switch_to` on the CPU switching
+// away from or to the thread, with interrupts disabled.
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    fn new(name: &str, stack: Option<KernelStack>, rsp: u64) -> Option<Arc<Self>> {
+        let fpu = FpuState::new()?;
+        let thread = Arc::new(Self {
+            id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            name: String::from(name),
+            cpu: percpu::cpu_id(),
+            sched: SchedState::new(),
+            state: AtomicU8::new(RUNNABLE),
+            context: UnsafeCell::new(Context { rsp, fpu: fpu.area, irq_depth: 0 }),
+            _fpu: fpu,
+            _stack: stack,
+        });
+        THREADS.lock().push((thread.id, Arc::downgrade(&thread)));
+        Some(thread)
+    }
+
+    /// A thread that will run `entry(arg)` on a stack of its own the first
+    /// time it is switched to.
+    fn with_entry(name: &str, entry: fn(usize), arg: usize) -> Option<Arc<Self>> {
+        let stack = KernelStack::new(THREAD_STACK_SIZE, name)?;
+
+        // The frame `_switch_to` pops: R15, R14, R13 (`arg`), R12
+        // (`entry`), RBX and RBP, then the return address.
+        let top = stack.top().as_mut_ptr::<u64>();
+        let frame = [0, 0, arg as u64, entry as usize as u64, 0, 0, _thread_entry as *const () as u64];
+        // SAFETY: the stack is ours and much larger than the frame.
+        let rsp = unsafe {
+            let rsp = top.sub(frame.len());
+            rsp.copy_from_nonoverlapping(frame.as_ptr(), frame.len());
+            rsp as u64
+        };
+
+        Self::new(name, Some(stack), rsp)
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Scheduling parameters and CPU time.
+    pub fn sched(&self) -> &SchedState {
+        &self.sched
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        THREADS.lock().retain(|(_, thread)| thread.strong_count() > 0);
+    }
+}
+
+/// One CPU's threads.
+struct RunQueue {
+    /// Real-time threads waiting for the CPU.
+    fifo: FifoScheduler,
+    /// Fair-share threads waiting for the CPU.
+    fair: FairScheduler,
+    /// The thread running on the CPU.
+    current: Option<Arc<Thread>>,
+    /// The thread just switched away from, kept alive until the switch is
+    /// complete.  Dropping it frees an exited thread's stack, which cannot
+    /// happen while that stack is still in use.
+    previous: Option<Arc<Thread>>,
+    /// Runs when nothing else is ready; never queued.
+    idle: Option<Arc<Thread>>,
+    /// [`tsc::monotonic_ns`] when CPU time was last charged.
+    charged_at: u64,
+    /// How long the current thread has had the CPU, in nanoseconds.
+    turn_ns: u64,
+    /// Set by the timer when the current thread should be preempted.
+    need_resched: bool,
+}
+
+static RUN_QUEUES: [IrqSafeMutex<RunQueue>; MAX_CPUS] = [const {
+    IrqSafeMutex::new(RunQueue {
+        fifo: FifoScheduler::new(),
+        fair: FairScheduler::new(),
+        current: None,
+        previous: None,
+        idle: None,
+        charged_at: 0,
+        turn_ns: 0,
+        need_resched: false,
+    })
+}; MAX_CPUS];
+
+/// Position of `policy`'s class in [`RunQueue::classes`].
+fn class_index(policy: Policy) -> usize {
+    match policy {
+        Policy::Fifo(_) => 0,
+        Policy::Fair => 1,
+    }
+}
+
+impl RunQueue {
+    /// The scheduling classes, highest first.
+    fn classes(&self) -> [&dyn Scheduler; 2] {
+        [&self.fifo, &self.fair]
+    }
+
+    fn classes_mut(&mut self) -> [&mut dyn Scheduler; 2] {
+        [&mut self.fifo, &mut self.fair]
+    }
+
+    fn class_mut(&mut self, thread: &Thread) -> &mut dyn Scheduler {
+        let [fifo, fair] = self.classes_mut();
+        if class_index(thread.sched.policy()) == 0 { fifo } else { fair }
+    }
+
+    fn is_idle(&self, thread: &Thread) -> bool {
+        self.idle.as_ref().is_some_and(|idle| core::ptr::eq(Arc::as_ptr(idle), thread))
+    }
+
+    fn has_ready(&self) -> bool {
+        self.classes().iter().any(|class| !class.is_empty())
+    }
+
+    fn enqueue(&mut self, thread: Arc<Thread>) {
+        self.class_mut(&thread).enqueue(thread);
+    }
+
+    fn remove(&mut self, thread: &Thread) -> bool {
+        self.class_mut(thread).remove(thread)
+    }
+
+    fn pick_next(&mut self) -> Option<Arc<Thread>> {
+        self.classes_mut().into_iter().find_map(|class| class.pick_next())
+    }
+
+    /// Returns `true` if a queued thread should take the CPU from
+    /// `current` right away: it is of a higher class, or its class says
+    /// so.
+    fn outranked(&self, current: &Thread) -> bool {
+        let index = class_index(current.sched.policy());
+        let classes = self.classes();
+        classes[..index].iter().any(|class| !class.is_empty()) || classes[index].should_preempt(current)
+    }
+
+    /// Charge the CPU time since the last charge to `current`.
+    fn charge(&mut self, current: &Thread) {
+        let now = tsc::monotonic_ns();
+        let ran = now.saturating_sub(self.charged_at);
+        self.charged_at = now;
+        self.turn_ns += ran;
+        current.sched.add_cpu_time(ran);
+        if !self.is_idle(current) {
+            self.class_mut(current).charge(current, ran);
+        }
+    }
+
+    /// Make the next thread current, queueing the current one again first
+    /// if `requeue` is set.  Returns the old and the new current thread, or
+    /// `None` if the current thread should keep running.
+    fn rotate(&mut self, requeue: bool) -> Option<(Arc<Thread>, Arc<Thread>)> {
+        let prev = self.current.take()?;
+        self.charge(&prev);
+        if requeue && !self.is_idle(&prev) {
+            self.enqueue(prev.clone());
+        }
+        // With `requeue` the current thread competes with the others and
+        // may well be picked again.  Without, it is leaving the CPU, and
+        // if nothing else is ready the idle thread takes over.
+        let next = self.pick_next().or_else(|| self.idle.clone()).filter(|next| !Arc::ptr_eq(next, &prev));
+        self.turn_ns = 0;
+        self.need_resched = false;
+        match next {
+            Some(next) => {
+                self.current = Some(next.clone());
+                Some((prev, next))
+            }
+            None => {
+                self.current = Some(prev);
+                None
+            }
+        }
+    }
+}
+
+// `_switch_to(prev: *mut Context, next: *const Context)`: save the
+// callee-saved registers, extended state and interrupt depth of the running
+// thread into `prev`, then resume the thread described by `next`.  `xsave`
+// and `xrstor` take the component mask in EDX:EAX; all ones means
+// everything XCR0 enables.
+core::arch::global_asm!(
+    ".global _switch_to",
+    "_switch_to:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rcx, [rdi + {ctx_fpu}]",
+    "mov eax, -1",
+    "mov edx, -1",
+    "cmp byte ptr [rip + {use_xsave}], 0",
+    "je 2f",
+    "xsave64 [rcx]",
+    "jmp 3f",
+    "2:",
+    "fxsave64 [rcx]",
+    "3:",
+    "mov rax, gs:[{irq_depth}]",
+    "mov [rdi + {ctx_irq_depth}], rax",
+    "mov [rdi + {ctx_rsp}], rsp",
+    "mov rsp, [rsi + {ctx_rsp}]",
+    "mov rax, [rsi + {ctx_irq_depth}]",
+    "mov gs:[{irq_depth}], rax",
+    "mov rcx, [rsi + {ctx_fpu}]",
+    "mov eax, -1",
+    "cmp byte ptr [rip + {use_xsave}], 0",
+    "je 4f",
+    "xrstor64 [rcx]",
+    "jmp 5f",
+    "4:",
+    "fxrstor64 [rcx]",
+    "5:",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    ctx_rsp = const offset_of!(Context, rsp),
+    ctx_fpu = const offset_of!(Context, fpu),
+    ctx_irq_depth = const offset_of!(Context, irq_depth),
+    irq_depth = const percpu::IRQ_DEPTH_OFFSET,
+    use_xsave = sym USE_XSAVE,
+);
+
+// First code a spawned thread runs, "returned to" by `_switch_to`.  The
+// initial stack frame leaves the entry point in R12 and its argument in
+// R13, and RSP at the (16-byte aligned) top of the stack.
+core::arch::global_asm!(
+    ".global _thread_entry",
+    "_thread_entry:",
+    "mov rdi, r12",
+    "mov rsi, r13",
+    "call {thread_start}",
+    "ud2",
+    thread_start = sym thread_start,
+);
+
+unsafe extern "C" {
+    fn _switch_to(prev: *mut Context, next: *const Context);
+    fn _thread_entry();
+}
+
+/// Rust side of `_thread_entry`.
+extern "C" fn thread_start(entry: usize, arg: usize) -> ! {
+    // SAFETY: `spawn` put a `fn(usize)` address in R12.
+    let entry = unsafe { core::mem::transmute::<usize, fn(usize)>(entry) };
+    finish_switch();
+    // The thread that switched to us did so with interrupts disabled.
+    interrupts::enable();
+    entry(arg);
+    exit()
+}
+
+/// Adopt the code running on the calling CPU as its first thread, named
+/// `name`, and give the CPU an idle thread.  `stack` is the stack that
+/// code runs on, if the thread is to own it.
+///
+/// # Safety
+/// Must be called once per CPU, after [`crate::heap::init`] and
+/// [`cpu::init`] (or [`cpu::init_ap`]).
+pub unsafe fn init(name: &str, stack: Option<KernelStack>) {
+    USE_XSAVE.store(cpu::info().xcr0 != 0, Ordering::Relaxed);
+
+    let cpu = percpu::cpu_id();
+    // The context is filled in by the first switch away from this thread.
+    let (Some(thread), Some(idle)) =
+        (Thread::new(name, stack, 0), Thread::with_entry(&format!("idle/{cpu}"), idle_loop, 0))
+    else {
+        panic!("thread: out of memory setting up CPU {cpu}");
+    };
+    percpu::set_current_task(Arc::as_ptr(&thread) as usize);
+    let mut queue = RUN_QUEUES[cpu].lock();
+    queue.current = Some(thread);
+    queue.idle = Some(idle);
+}
+
+/// Body of every CPU's idle thread.
+fn idle_loop(_: usize) {
+    loop {
+        // Check with interrupts off, so an interrupt that makes a thread
+        // ready right after the check still ends the `hlt`.
+        interrupts::disable();
+        if !RUN_QUEUES[percpu::cpu_id()].lock().has_ready() {
+            interrupts::enable_and_halt();
+        } else {
+            interrupts::enable();
+            yield_now();
+        }
+    }
+}
+
+/// Start a thread named `name` that runs `entry(arg)` on the calling CPU.
+///
+/// The thread starts out with the fair policy and nice 0, and exits when
+/// `entry` returns.  Returns `None` if its stack or state could not be
+/// allocated.
+pub fn spawn(name: &str, entry: fn(usize), arg: usize) -> Option<ThreadId> {
+    let thread = Thread::with_entry(name, entry, arg)?;
+    let id = thread.id;
+    RUN_QUEUES[percpu::cpu_id()].lock().enqueue(thread);
+    Some(id)
+}
+
+/// The live thread with ID `id`.
+fn find(id: ThreadId) -> Option<Arc<Thread>> {
+    THREADS.lock().iter().find(|(thread_id, _)| *thread_id == id).and_then(|(_, thread)| thread.upgrade())
+}
+
+/// Change thread `id`'s parameters with `change`, taking it out of its run
+/// queue meanwhile, then yield if it now outranks the calling thread.
+fn reschedule(id: ThreadId, change: impl FnOnce(&SchedState) -> Result<(), SchedError>) -> Result<(), SchedError> {
+    let thread = find(id).ok_or(SchedError::NoSuchThread)?;
+    let mut queue = RUN_QUEUES[thread.cpu].lock();
+    let queued = queue.remove(&thread);
+    let result = change(&thread.sched);
+    if queued {
+        queue.enqueue(thread.clone());
+    }
+    let preempt = thread.cpu == percpu::cpu_id()
+        && queue.current.as_ref().is_some_and(|current| !queue.is_idle(current) && queue.outranked(current));
+    drop(queue);
+    drop(thread);
+
+    if preempt && !percpu::in_interrupt() {
+        yield_now();
+    }
+    result
+}
+
+/// Change thread `id`'s scheduling policy.
+pub fn set_policy(id: ThreadId, policy: Policy) -> Result<(), SchedError> {
+    reschedule(id, |sched| sched.set_policy(policy))
+}
+
+/// Change thread `id`'s nice value, from [`crate::sched::MIN_NICE`] to
+/// [`crate::sched::MAX_NICE`].  Only matters under [`Policy::Fair`].
+pub fn set_nice(id: ThreadId, nice: i8) -> Result<(), SchedError> {
+    reschedule(id, |sched| sched.set_nice(nice))
+}
+
+/// A snapshot of one thread, as returned by [`threads`].
+#[derive(Clone, Debug)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub cpu: usize,
+    pub policy: Policy,
+    pub nice: i8,
+    /// CPU time used, up to the last timer tick or switch.
+    pub cpu_time_ns: u64,
+}
+
+/// Every live thread, oldest first.
+pub fn threads() -> Vec<ThreadInfo> {
+    let threads: Vec<Arc<Thread>> = THREADS.lock().iter().filter_map(|(_, thread)| thread.upgrade()).collect();
+    threads
+        .iter()
+        .map(|thread| ThreadInfo {
+            id: thread.id,
+            name: thread.name.clone(),
+            cpu: thread.cpu,
+            policy: thread.sched.policy(),
+            nice: thread.sched.nice(),
+            cpu_time_ns: thread.sched.cpu_time_ns(),
+        })
+        .collect()
+}
+
+/// ID of the thread running on the calling CPU, or `None` before [`init`].
+pub fn current_id() -> Option<ThreadId> {
+    current_ptr().map(|thread| thread.id)
+}
+
+/// Set the time slice of fair-share threads, in milliseconds.  Takes
+/// effect from the next timer tick on each CPU.
+pub fn set_time_slice_ms(ms: u64) {
+    TIME_SLICE_MS.store(ms.max(1), Ordering::Relaxed);
+}
+
+/// Current time slice in milliseconds.
+pub fn time_slice_ms() -> u64 {
+    TIME_SLICE_MS.load(Ordering::Relaxed)
+}
+
+/// Switch to the next thread on the calling CPU.  The current thread is
+/// queued again if `requeue` is set; otherwise it leaves the run queue
+/// once the switch is complete, and the idle thread takes over if nothing
+/// else is ready.
+///
+/// Returns `false` without switching if the current thread keeps running.
+fn switch(requeue: bool) -> bool {
+    switch_if(requeue, |_| true)
+}
+
+/// Like [`switch`], but only if `check`, called with the run queue locked
+/// and given the current thread, agrees.
+fn switch_if(requeue: bool, check: impl FnOnce(&Thread) -> bool) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut queue = RUN_QUEUES[percpu::cpu_id()].lock();
+        if !queue.current.as_deref().is_some_and(check) {
+            return false;
+        }
+        let Some((prev, next)) = queue.rotate(requeue) else { return false };
+
+        let prev_context = prev.context.get();
+        let next_context = next.context.get();
+        percpu::set_current_task(Arc::as_ptr(&next) as usize);
+        queue.previous = Some(prev);
+        drop(next);
+        drop(queue);
+
+        // SAFETY: both contexts are kept alive by the run queue, `prev` is
+        // the running thread, and interrupts stay off until the other
+        // side of the switch has finished it.
+        unsafe { _switch_to(prev_context, next_context) };
+        finish_switch();
+        true
+    })
+}
+
+/// Complete a switch on the new thread's side: release the thread
+/// switched away from.
+fn finish_switch() {
+    let previous = RUN_QUEUES[percpu::cpu_id()].lock().previous.take();
+    drop(previous);
+}
+
+/// Let another thread on this CPU run, if the scheduler picks one over
+/// the calling thread.  A real-time thread only yields to threads of the
+/// same or higher priority.
+pub fn yield_now() {
+    switch(true);
+}
+
+/// The calling thread, or `None` before [`init`].
+pub fn current() -> Option<Arc<Thread>> {
+    RUN_QUEUES[percpu::cpu_id()].lock().current.clone()
+}
+
+/// Announce that the calling thread is about to [`block`], and return it
+/// so a waker can find it.  A [`wake`] from here on makes [`block`] return
+/// straight away, so a wake-up that comes between checking a condition
+/// and blocking on it is not lost.
+///
+/// Returns `None` before [`init`], when there is no thread to block.
+pub fn prepare_to_wait() -> Option<Arc<Thread>> {
+    let queue = RUN_QUEUES[percpu::cpu_id()].lock();
+    let current = queue.current.clone()?;
+    current.state.store(WAITING, Ordering::Relaxed);
+    Some(current)
+}
+
+/// Undo [`prepare_to_wait`] without blocking.
+pub fn cancel_wait() {
+    let _queue = RUN_QUEUES[percpu::cpu_id()].lock();
+    if let Some(current) = current_ptr() {
+        current.state.store(RUNNABLE, Ordering::Relaxed);
+    }
+}
+
+/// Block the calling thread until [`wake`] is called on it, unless that
+/// already happened since [`prepare_to_wait`].
+pub fn block() {
+    switch_if(false, |current| {
+        let waiting = current.state.load(Ordering::Relaxed) == WAITING;
+        if waiting {
+            current.state.store(BLOCKED, Ordering::Relaxed);
+        }
+        waiting
+    });
+}
+
+/// Like [`block`], but wake up by `deadline_ns`, in [`tsc::monotonic_ns`]
+/// time, at the latest.  Deadlines are checked on every PIT tick.
+///
+/// # Panics
+/// If the PIT is not running: nothing would ever check the deadline.
+pub fn block_until(deadline_ns: u64) {
+    let Some(current) = current() else { return };
+    assert!(pit::is_running(), "thread: timed wait without a PIT tick to time it");
+    TIMEOUTS.lock().push((deadline_ns, current.clone()));
+    block();
+    TIMEOUTS.lock().retain(|(_, thread)| !Arc::ptr_eq(thread, &current));
+}
+
+/// Wake every thread in [`block_until`] whose deadline has passed.
+fn expire_timeouts() {
+    let now = tsc::monotonic_ns();
+    let mut expired = Vec::new();
+    TIMEOUTS.lock().retain(|(deadline, thread)| {
+        if *deadline <= now {
+            expired.push(thread.clone());
+        }
+        *deadline > now
+    });
+    for thread in &expired {
+        wake(thread);
+    }
+}
+
+/// Make `thread` runnable if it is waiting or blocked.  Returns `false` if
+/// it was neither.
+///
+/// Safe from interrupt handlers.  A woken thread that should run before
+/// the one it interrupted takes over when the handler returns.
+pub fn wake(thread: &Arc<Thread>) -> bool {
+    let mut queue = RUN_QUEUES[thread.cpu].lock();
+    match thread.state.swap(RUNNABLE, Ordering::Relaxed) {
+        WAITING => true,
+        BLOCKED => {
+            queue.enqueue(thread.clone());
+            if let Some(current) = queue.current.clone()
+                && (queue.is_idle(&current) || queue.outranked(&current))
+            {
+                queue.need_resched = true;
+            }
+            drop(queue);
+            if thread.cpu != percpu::cpu_id() {
+                smp::kick(thread.cpu);
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The calling CPU's current thread, without touching its reference count.
+fn current_ptr() -> Option<&'static Thread> {
+    let thread = percpu::current_task() as *const Thread;
+    // SAFETY: the run queue keeps the current thread alive, and the caller
+    // is that thread.
+    unsafe { thread.as_ref() }
+}
+
+/// End the calling thread.
+///
+/// # Panics
+/// If called by an idle thread, or before [`init`].
+pub fn exit() -> ! {
+    switch(false);
+    panic!("thread: exit with no thread to take over");
+}
+
+/// Charge CPU time to the current thread and decide whether it should be
+/// preempted, and end expired [`block_until`] waits.  Called by the timer
+/// interrupt handler of every CPU.
+pub fn timer_tick() {
+    expire_timeouts();
+
+    let mut queue = RUN_QUEUES[percpu::cpu_id()].lock();
+    let Some(current) = queue.current.clone() else { return };
+    queue.charge(&current);
+    if queue.is_idle(&current) {
+        queue.need_resched = queue.has_ready();
+        return;
+    }
+
+    let index = class_index(current.sched.policy());
+    let expired = queue.classes()[index].time_slice_ns(&current).is_some_and(|slice| queue.turn_ns >= slice);
+    if queue.outranked(&current) || expired && queue.has_ready() {
+        queue.need_resched = true;
+    } else if expired {
+        // With nothing else ready, the thread just gets another slice.
+        queue.turn_ns = 0;
+    }
+}
+
+/// Switch threads if the timer or a [`wake`] asked for it.  Called on the
+/// way out of every interrupt, but only acts in the outermost one, so a
+/// switch never strands a half-finished handler.
+pub fn preempt() {
+    if percpu::irq_depth() != 1 {
+        return;
+    }
+    let need_resched = core::mem::take(&mut RUN_QUEUES[percpu::cpu_id()].lock().need_resched);
+    if need_resched {
+        switch(true);
+    }
+}
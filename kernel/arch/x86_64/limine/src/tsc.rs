@@ -0,0 +1,161 @@
+//! Time Stamp Counter clock.
+//!
+//! The TSC frequency is taken from CPUID leaf 0x15 when the CPU reports it,
+//! and otherwise calibrated against the HPET, or PIT channel 2 without one.
+//! [`monotonic_ns`] turns a TSC reading into nanoseconds with one multiply
+//! and shift, which makes it cheap enough for scheduler accounting and log
+//! timestamps.  Without an invariant TSC it falls back to the PIT tick
+//! counter.
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::cpu::{self, Feature};
+use crate::hpet;
+use crate::pit;
+use crate::port::{inb, outb};
+
+const CPUID_TSC_CRYSTAL: u32 = 0x15;
+
+const PIT_CHANNEL2_DATA: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const PIT_CMD_CHANNEL2_ONESHOT: u8 = 0xB0;
+/// Keyboard controller port B: bit 0 gates channel 2, bit 1 drives the
+/// speaker, bit 5 reflects channel 2's output.
+const PORT_B: u16 = 0x61;
+const PORT_B_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 = 1 << 5;
+
+/// Length of one calibration run.
+const CALIBRATION_MS: u32 = 10;
+const CALIBRATION_RUNS: usize = 3;
+
+static FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+/// TSC value that corresponds to `monotonic_ns() == 0`.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// Nanoseconds per TSC cycle as a 32.32 fixed-point number.
+static NS_MULT: AtomicU64 = AtomicU64::new(0);
+
+/// Read the TSC.  `lfence` keeps earlier loads from drifting past it.
+#[inline]
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!(
+            "lfence",
+            "rdtsc",
+            out("eax") low,
+            out("edx") high,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// TSC frequency from CPUID leaf 0x15 (crystal clock × ratio), if the CPU
+/// enumerates all three values.
+fn frequency_from_cpuid() -> Option<u64> {
+    if cpu::info().max_leaf < CPUID_TSC_CRYSTAL {
+        return None;
+    }
+    let leaf = __cpuid(CPUID_TSC_CRYSTAL);
+    if leaf.eax == 0 || leaf.ebx == 0 || leaf.ecx == 0 {
+        return None;
+    }
+    Some(leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64)
+}
+
+/// Count TSC cycles across `CALIBRATION_MS` of HPET counter ticks.
+fn measure_with_hpet() -> u64 {
+    let target = hpet::frequency_hz() * CALIBRATION_MS as u64 / 1000;
+    let start_count = hpet::counter();
+    let start = rdtsc();
+    while hpet::elapsed(start_count) < target {
+        core::hint::spin_loop();
+    }
+    rdtsc() - start
+}
+
+/// Count TSC cycles across one `CALIBRATION_MS` one-shot of PIT channel 2.
+fn measure_with_pit() -> u64 {
+    let count = (pit::BASE_FREQUENCY_HZ * CALIBRATION_MS / 1000) as u16;
+
+    // SAFETY: port I/O on PIT channel 2 and port B; channel 2 is not used
+    // for anything else.
+    unsafe {
+        // Gate off, speaker off while we program the counter.
+        let port_b = inb(PORT_B) & !(PORT_B_GATE | PORT_B_SPEAKER);
+        outb(PORT_B, port_b);
+
+        outb(PIT_COMMAND, PIT_CMD_CHANNEL2_ONESHOT);
+        outb(PIT_CHANNEL2_DATA, count as u8);
+        outb(PIT_CHANNEL2_DATA, (count >> 8) as u8);
+
+        // Raising the gate starts the countdown; OUT2 goes high at zero.
+        outb(PORT_B, port_b | PORT_B_GATE);
+        let start = rdtsc();
+        while inb(PORT_B) & PORT_B_OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+        let end = rdtsc();
+
+        outb(PORT_B, port_b);
+        end - start
+    }
+}
+
+/// Calibrate against the HPET if there is one, else the PIT, keeping the
+/// shortest run: interruptions and virtualisation exits only ever make a
+/// run longer.
+fn frequency_from_timer() -> u64 {
+    let measure = if hpet::is_available() { measure_with_hpet } else { measure_with_pit };
+    let cycles = (0..CALIBRATION_RUNS).map(|_| measure()).min().unwrap_or(0);
+    cycles * 1000 / CALIBRATION_MS as u64
+}
+
+/// Use `hz` as the TSC frequency and restart the clock at zero.
+fn set_frequency(hz: u64) {
+    FREQUENCY_HZ.store(hz, Ordering::Relaxed);
+    NS_MULT.store((1_000_000_000u64 << 32).checked_div(hz).unwrap_or(0), Ordering::Relaxed);
+    BASE.store(rdtsc(), Ordering::Release);
+}
+
+/// Detect an invariant TSC and determine its frequency.
+///
+/// Should run with interrupts disabled so calibration isn't stretched, and
+/// after [`cpu::init`].
+pub fn init() {
+    INVARIANT.store(cpu::has(Feature::InvariantTsc), Ordering::Relaxed);
+    set_frequency(frequency_from_cpuid().unwrap_or_else(frequency_from_timer));
+}
+
+/// TSC frequency in Hz, or zero before [`init`].
+pub fn frequency_hz() -> u64 {
+    FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if the TSC ticks at a constant rate regardless of power
+/// state, which [`monotonic_ns`] needs to use it.
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since [`init`].
+///
+/// Backed by the TSC when it is invariant and calibrated, otherwise by the
+/// PIT tick counter (at tick resolution).
+pub fn monotonic_ns() -> u64 {
+    let mult = NS_MULT.load(Ordering::Relaxed);
+    if is_invariant() && mult != 0 {
+        let delta = rdtsc().wrapping_sub(BASE.load(Ordering::Acquire));
+        ((delta as u128 * mult as u128) >> 32) as u64
+    } else {
+        match pit::frequency() {
+            0 => 0,
+            hz => (pit::ticks() as u128 * 1_000_000_000 / hz as u128) as u64,
+        }
+    }
+}
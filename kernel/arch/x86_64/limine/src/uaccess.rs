@@ -0,0 +1,147 @@
+//! SMEP/SMAP and checked access to user memory.
+//!
+//! With SMEP the kernel faults when it executes a user page; with SMAP it
+//! faults when it touches one at all, unless RFLAGS.AC is set.  The only
+//! sanctioned way to read or write user memory is [`copy_from_user`] and
+//! [`copy_to_user`]: they check that the range lies in the lower half, set
+//! AC with `stac` for the duration of the copy only, and turn a page fault
+//! inside the copy into [`UaccessError::Fault`] instead of a crash.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu::{self, Feature};
+
+const CR4_SMEP: u64 = 1 << 20;
+const CR4_SMAP: u64 = 1 << 21;
+
+/// First address above the lower (user) half of the address space.
+const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// Whether [`init`] turned SMAP on; read by the interrupt path, which
+/// must only `clac` when it is.
+pub(crate) static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Errors returned by the user-copy helpers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UaccessError {
+    /// The range is not entirely within user space.
+    InvalidRange,
+    /// Part of the range is not mapped (or not writable, for
+    /// [`copy_to_user`]).
+    Fault,
+}
+
+// `rep movsb` between two buffers; returns the number of bytes left
+// uncopied in rax.  A page fault on the `rep movsb` resumes at
+// `_user_copy_fault` (see `fixup`), with rcx still counting what is left.
+core::arch::global_asm!(
+    ".global _user_copy",
+    "_user_copy:",
+    "mov rcx, rdx",
+    ".global _user_copy_insn",
+    "_user_copy_insn:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    ".global _user_copy_fault",
+    "_user_copy_fault:",
+    "mov rax, rcx",
+    "ret",
+);
+
+unsafe extern "C" {
+    fn _user_copy(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    fn _user_copy_insn();
+    fn _user_copy_fault();
+}
+
+fn read_cr4() -> u64 {
+    let value: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    unsafe {
+        core::arch::asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+/// Turn on SMEP and SMAP on the calling CPU, where supported.
+///
+/// # Safety
+/// Must be called on every CPU during boot, before anything runs in user
+/// mode.
+pub unsafe fn init() {
+    let mut cr4 = read_cr4();
+    if cpu::has(Feature::Smep) {
+        cr4 |= CR4_SMEP;
+    }
+    if cpu::has(Feature::Smap) {
+        cr4 |= CR4_SMAP;
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+    unsafe { write_cr4(cr4) };
+}
+
+/// Returns `true` if SMEP is on for the calling CPU.
+pub fn smep_enabled() -> bool {
+    read_cr4() & CR4_SMEP != 0
+}
+
+/// Returns `true` if SMAP is on for the calling CPU.
+pub fn smap_enabled() -> bool {
+    read_cr4() & CR4_SMAP != 0
+}
+
+/// Returns `true` if `addr..addr + len` lies within user space.
+pub fn is_user_range(addr: u64, len: usize) -> bool {
+    addr.checked_add(len as u64).is_some_and(|end| end <= USER_END)
+}
+
+/// Copy `len` bytes with user access enabled.
+fn copy(dst: *mut u8, src: *const u8, len: usize) -> Result<(), UaccessError> {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    // SAFETY: the caller checked the user side of the range; the kernel
+    // side is a live slice.  `stac`/`clac` only exist with SMAP.
+    let left = unsafe {
+        if smap {
+            core::arch::asm!("stac", options(nostack));
+        }
+        let left = _user_copy(dst, src, len);
+        if smap {
+            core::arch::asm!("clac", options(nostack));
+        }
+        left
+    };
+    if left == 0 { Ok(()) } else { Err(UaccessError::Fault) }
+}
+
+/// Copy `dst.len()` bytes from user address `src` into `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), UaccessError> {
+    if !is_user_range(src, dst.len()) {
+        return Err(UaccessError::InvalidRange);
+    }
+    copy(dst.as_mut_ptr(), src as *const u8, dst.len())
+}
+
+/// Copy `src` to user address `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), UaccessError> {
+    if !is_user_range(dst, src.len()) {
+        return Err(UaccessError::InvalidRange);
+    }
+    copy(dst as *mut u8, src.as_ptr(), src.len())
+}
+
+/// Where to resume after a page fault at `rip`, if it happened inside a
+/// user copy.
+pub fn fixup(rip: u64) -> Option<u64> {
+    (rip == _user_copy_insn as *const () as u64).then_some(_user_copy_fault as *const () as u64)
+}
+
+/// Returns `true` if `addr` is in the user half of the address space.
+pub fn is_user_address(addr: u64) -> bool {
+    addr < USER_END
+}
@@ -0,0 +1,103 @@
+//! Wait queues.
+//!
+//! A [`WaitQueue`] is a list of threads blocked until some condition
+//! holds.  Whoever makes the condition true calls [`WaitQueue::wake_one`]
+//! or [`WaitQueue::wake_all`], interrupt handlers included.  Waiters
+//! re-check the condition after every wake-up, so spurious or stolen
+//! wake-ups are harmless.  Timed waits give up at a deadline measured with
+//! [`tsc::monotonic_ns`], at PIT-tick resolution.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, Thread};
+use crate::tsc;
+
+/// Threads waiting for a condition.
+pub struct WaitQueue {
+    waiters: IrqSafeMutex<VecDeque<Arc<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self { waiters: IrqSafeMutex::new(VecDeque::new()) }
+    }
+
+    /// Block the calling thread until `condition` returns `true`.
+    ///
+    /// `condition` is checked first, and again after every wake-up.  It may
+    /// also claim what it checks for, e.g. take a lock that it finds free.
+    /// Before [`thread::init`] there is no thread to block, and this spins.
+    pub fn wait_event(&self, condition: impl FnMut() -> bool) {
+        self.wait(condition, None);
+    }
+
+    /// Like [`wait_event`](Self::wait_event), but give up after
+    /// `timeout_ms` milliseconds.  Returns `false` if it timed out.
+    ///
+    /// # Panics
+    /// If it has to block while the PIT is not running, as
+    /// [`thread::block_until`] does.
+    pub fn wait_event_timeout(&self, condition: impl FnMut() -> bool, timeout_ms: u64) -> bool {
+        let deadline = tsc::monotonic_ns().saturating_add(timeout_ms.saturating_mul(1_000_000));
+        self.wait(condition, Some(deadline))
+    }
+
+    fn wait(&self, mut condition: impl FnMut() -> bool, deadline: Option<u64>) -> bool {
+        loop {
+            // Checked before the deadline, so a wake-up that arrives just as
+            // the wait times out is not lost.
+            if condition() {
+                return true;
+            }
+            if deadline.is_some_and(|deadline| tsc::monotonic_ns() >= deadline) {
+                return false;
+            }
+            let Some(current) = thread::prepare_to_wait() else {
+                core::hint::spin_loop();
+                continue;
+            };
+            self.waiters.lock().push_back(current.clone());
+
+            // A wake-up from here on makes `block` return at once.
+            if condition() {
+                thread::cancel_wait();
+                self.waiters.lock().retain(|waiter| !Arc::ptr_eq(waiter, &current));
+                return true;
+            }
+            match deadline {
+                Some(deadline) => {
+                    thread::block_until(deadline);
+                    // Still queued if the deadline woke us.
+                    self.waiters.lock().retain(|waiter| !Arc::ptr_eq(waiter, &current));
+                }
+                None => thread::block(),
+            }
+        }
+    }
+
+    /// Wake the thread that has waited longest.  Returns `false` if no
+    /// thread was waiting.
+    pub fn wake_one(&self) -> bool {
+        loop {
+            let Some(waiter) = self.waiters.lock().pop_front() else { return false };
+            // Skip threads that stopped waiting without being dequeued.
+            if thread::wake(&waiter) {
+                return true;
+            }
+        }
+    }
+
+    /// Wake every waiting thread.  Returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        waiters.iter().filter(|waiter| thread::wake(waiter)).count()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,16 +1,42 @@
 #![no_std]
 
+extern crate alloc;
 extern crate limine;
 
+use alloc::vec::Vec;
+
 use limine::framebuffer_info;
-use spin::Mutex;
+use limine::sync::IrqSafeMutex;
 
 mod font;
 
 const FONT_WIDTH: usize = 8;
 const FONT_HEIGHT: usize = 16;
 
-pub static TERMINAL: Mutex<Terminal> = Mutex::new(Terminal::new());
+/// The framebuffer console.  Interrupt handlers print too, so it is held
+/// with interrupts disabled.
+pub static TERMINAL: IrqSafeMutex<Terminal> = IrqSafeMutex::new(Terminal::new());
+
+/// How long [`terminal_seize`] waits for the lock before breaking it.
+const SEIZE_SPINS: usize = 10_000_000;
+
+/// Make the terminal printable for a fatal error report, from code that
+/// cannot name [`TERMINAL`] (see `limine::console::seize`).  A holder on
+/// another CPU gets a moment to finish drawing; if the lock is still taken
+/// after that, its holder is most likely the code that crashed, and the
+/// lock is broken.
+#[unsafe(no_mangle)]
+pub extern "C" fn terminal_seize() {
+    for _ in 0..SEIZE_SPINS {
+        if !TERMINAL.is_locked() {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    // SAFETY: the caller is reporting a fatal error and halts afterwards;
+    // a holder that comes back only finds the cursor moved.
+    unsafe { TERMINAL.force_unlock() };
+}
 
 /// Standard VGA 16-colour palette → 32-bit 0x00RRGGBB.
 const VGA_PALETTE: [u32; 16] = [
@@ -39,6 +65,10 @@ pub struct Terminal {
     bg: u32,
     cols: usize,
     rows: usize,
+    /// Copy of the framebuffer in RAM, laid out like it, once
+    /// [`Terminal::enable_shadow`] has run.  Video memory is uncached
+    /// (write-combining at best), so scrolling reads this instead.
+    shadow: Vec<u32>,
 }
 
 impl Terminal {
@@ -50,7 +80,25 @@ impl Terminal {
             bg: 0x000000,  // black
             cols: 0,
             rows: 0,
+            shadow: Vec::new(),
+        }
+    }
+
+    /// Keep a copy of the screen in RAM from now on, so that scrolling
+    /// only ever writes to video memory.  Needs the kernel heap; returns
+    /// `false` if there is no framebuffer or the copy cannot be allocated.
+    pub fn enable_shadow(&mut self) -> bool {
+        let Some(fb) = framebuffer_info() else { return false };
+        let pixels = fb.height as usize * (fb.pitch as usize / 4);
+        let mut shadow = Vec::new();
+        if shadow.try_reserve_exact(pixels).is_err() {
+            return false;
         }
+        // Start from what is on screen: the last read of video memory.
+        let base = fb.address as *const u32;
+        shadow.extend((0..pixels).map(|i| unsafe { core::ptr::read_volatile(base.add(i)) }));
+        self.shadow = shadow;
+        true
     }
 
     /// Lazily compute the text-grid dimensions from the framebuffer.
@@ -72,7 +120,7 @@ impl Terminal {
             let pixels = fb.height as usize * (fb.pitch as usize / 4);
             let ptr = fb.address as *mut u32;
             for i in 0..pixels {
-                unsafe { core::ptr::write_volatile(ptr.add(i), self.bg); }
+                self.put_pixel(ptr, i, self.bg);
             }
 
             self.row = 0;
@@ -91,7 +139,7 @@ impl Terminal {
     /// Draw character `c` at text-grid position (x, y) with the given
     /// VGA attribute byte.
     #[unsafe(no_mangle)]
-    pub fn put_entry_at(&self, c: u8, color: u8, x: usize, y: usize) {
+    pub fn put_entry_at(&mut self, c: u8, color: u8, x: usize, y: usize) {
         let fg = VGA_PALETTE[(color & 0x0F) as usize];
         let bg = VGA_PALETTE[((color >> 4) & 0x0F) as usize];
         self.draw_char(c, fg, bg, x, y);
@@ -99,8 +147,17 @@ impl Terminal {
 
     // ── internal helpers ────────────────────────────────────────────
 
+    /// Set the pixel at `offset` (in pixels from `base`, the framebuffer),
+    /// and its copy in the shadow buffer.
+    fn put_pixel(&mut self, base: *mut u32, offset: usize, pixel: u32) {
+        if let Some(copy) = self.shadow.get_mut(offset) {
+            *copy = pixel;
+        }
+        unsafe { core::ptr::write_volatile(base.add(offset), pixel); }
+    }
+
     /// Blit an 8x8 glyph at text-grid position (col, row).
-    fn draw_char(&self, c: u8, fg: u32, bg: u32, col: usize, row: usize) {
+    fn draw_char(&mut self, c: u8, fg: u32, bg: u32, col: usize, row: usize) {
         if let Some(fb) = framebuffer_info() {
             let glyph = font::get_glyph(c);
             let px = col * FONT_WIDTH;
@@ -113,7 +170,7 @@ impl Terminal {
                 for gx in 0..FONT_WIDTH {
                     let pixel = if bits & (0x80 >> gx) != 0 { fg } else { bg };
                     let off = (py + gy) * stride + (px + gx);
-                    unsafe { core::ptr::write_volatile(base.add(off), pixel); }
+                    self.put_pixel(base, off, pixel);
                 }
             }
         }
@@ -122,19 +179,23 @@ impl Terminal {
     /// Scroll the entire screen up by one text row.
     fn move_up(&mut self) {
         if let Some(fb) = framebuffer_info() {
-            let pitch = fb.pitch as usize;
-            let base = fb.address as *mut u8;
-            let row_bytes = FONT_HEIGHT * pitch;
-            let total = (self.rows - 1) * row_bytes;
-
-            // Copy rows 1..n to rows 0..n-1.
-            unsafe { core::ptr::copy(base.add(row_bytes), base, total); }
+            let base = fb.address as *mut u32;
+            let row_pixels = FONT_HEIGHT * (fb.pitch as usize / 4);
+            let total = (self.rows - 1) * row_pixels;
+
+            // Copy rows 1..n to rows 0..n-1: within the shadow buffer and
+            // from there to video memory if there is one, or else reading
+            // video memory back, which is slow.
+            if self.shadow.is_empty() {
+                unsafe { core::ptr::copy(base.add(row_pixels), base, total); }
+            } else {
+                self.shadow.copy_within(row_pixels..row_pixels + total, 0);
+                unsafe { core::ptr::copy_nonoverlapping(self.shadow.as_ptr(), base, total); }
+            }
 
             // Clear the last text row.
-            let last = base.wrapping_add((self.rows - 1) * row_bytes) as *mut u32;
-            let pixels_in_row = FONT_HEIGHT * (pitch / 4);
-            for i in 0..pixels_in_row {
-                unsafe { core::ptr::write_volatile(last.add(i), self.bg); }
+            for i in total..total + row_pixels {
+                self.put_pixel(base, i, self.bg);
             }
         }
     }
@@ -1,5 +1,7 @@
 #![no_std]
 
+extern crate alloc;
+
 #[cfg(target_arch = "x86")]
 extern crate tty_i386;
 
@@ -11,7 +13,11 @@ use librust::printf::{ kprintln, kprint };
 use tty_i386::{ TERMINAL };
 
 #[cfg(target_arch = "x86_64")]
-use limine::{ init as init_x86_64, keyboard };
+use limine::{ init as init_x86_64, apic, cpu, heap, hpet, keyboard, pit, pmm, smp, tsc };
+#[cfg(target_arch = "x86_64")]
+use alloc::vec::Vec;
+#[cfg(target_arch = "x86_64")]
+use librust::tostring::{ u32_to_str, u64_to_str, u64_to_hex_str };
 
 #[unsafe(no_mangle)]
 pub extern "C" fn rust_eh_personality() {}
@@ -45,14 +51,105 @@ core::arch::global_asm!(
 
 #[cfg(target_arch = "x86_64")]
 #[unsafe(no_mangle)]
-pub extern "C" fn rust_kernel_main() {
-    init_x86_64();
+pub extern "C" fn rust_kernel_main() -> ! {
+    init_x86_64(main)
+}
+
+/// The BSP's first thread, once the kernel is up.
+#[cfg(target_arch = "x86_64")]
+fn main() -> ! {
+    // With the heap up, the terminal can keep a copy of the screen in RAM
+    // and stop reading video memory back when it scrolls.
+    tty_x86_64::TERMINAL.lock().enable_shadow();
 
     kprintln(b"Hello, Thaunos! This is the x86_64 kernel.");
+    if apic::is_enabled() {
+        kprintln(b"Interrupt controller: local APIC + I/O APIC");
+    } else {
+        kprintln(b"Interrupt controller: 8259 PIC");
+    }
+
+    let info = cpu::info();
+    let mut family_buf = [0u8; 12];
+    let mut model_buf = [0u8; 12];
+    kprint(b"CPU: ");
+    kprint(&info.vendor);
+    kprint(b" family ");
+    kprint(u32_to_str(info.family, &mut family_buf));
+    kprint(b" model ");
+    kprint(u32_to_str(info.model, &mut model_buf));
+    if !info.brand().is_empty() {
+        kprint(b" (");
+        kprint(info.brand());
+        kprint(b")");
+    }
+    kprintln(b"");
+
+    kprint(b"CPU features:");
+    for &feature in cpu::Feature::ALL {
+        if info.has(feature) {
+            kprint(b" ");
+            kprint(feature.name());
+        }
+    }
+    kprintln(b"");
+
+    if info.xcr0 != 0 {
+        let mut xsave_buf = [0u8; 20];
+        kprint(b"XSAVE: ");
+        kprint(u32_to_str(info.xsave_size, &mut xsave_buf));
+        kprint(b" bytes, XCR0 = 0x");
+        kprintln(u64_to_hex_str(info.xcr0, &mut xsave_buf));
+    }
+
+    let mut cpus_buf = [0u8; 12];
+    kprint(b"CPUs online: ");
+    kprintln(u32_to_str(smp::cpu_count() as u32, &mut cpus_buf));
+
+    let memory = pmm::stats();
+    let frames_per_mib = (1024 * 1024 / pmm::FRAME_SIZE) as usize;
+    let mut free_buf = [0u8; 20];
+    let mut total_buf = [0u8; 20];
+    kprint(b"Memory: ");
+    kprint(u64_to_str((memory.free_frames / frames_per_mib) as u64, &mut free_buf));
+    kprint(b" MiB free of ");
+    kprint(u64_to_str((memory.total_frames / frames_per_mib) as u64, &mut total_buf));
+    kprintln(b" MiB");
+
+    let heap = heap::stats();
+    let mut heap_buf = [0u8; 20];
+    kprint(b"Heap: ");
+    kprint(u64_to_str((heap.mapped_bytes / 1024) as u64, &mut heap_buf));
+    kprintln(b" KiB mapped");
+
+    let mut hz_buf = [0u8; 12];
+    kprint(b"System timer: PIT at ");
+    kprint(u32_to_str(pit::frequency(), &mut hz_buf));
+    kprintln(b" Hz");
+
+    if hpet::is_available() {
+        let mut hpet_buf = [0u8; 20];
+        let mut timers_buf = [0u8; 12];
+        kprint(b"HPET: ");
+        kprint(u64_to_str(hpet::frequency_hz(), &mut hpet_buf));
+        kprint(b" Hz, ");
+        kprint(u32_to_str(hpet::timer_count() as u32, &mut timers_buf));
+        kprintln(b" comparators");
+    } else {
+        kprintln(b"HPET: not present");
+    }
+
+    let mut mhz_buf = [0u8; 20];
+    kprint(b"TSC: ");
+    kprint(u64_to_str(tsc::frequency_hz() / 1_000_000, &mut mhz_buf));
+    if tsc::is_invariant() {
+        kprintln(b" MHz (invariant)");
+    } else {
+        kprintln(b" MHz (not invariant, clock uses PIT ticks)");
+    }
     kprintln(b"Keyboard input enabled. Type something:");
 
-    let mut input = [0u8; 256];
-    let mut input_len = 0;
+    let mut input = Vec::new();
 
     kprint(b"> ");
 
@@ -63,19 +160,16 @@ pub extern "C" fn rust_kernel_main() {
             kprintln(b"");
 
             kprint(b"You typed: ");
-            kprint(core::str::from_utf8(&input[..input_len]).unwrap_or("<invalid UTF-8>").as_bytes());
+            kprint(core::str::from_utf8(&input).unwrap_or("<invalid UTF-8>").as_bytes());
             kprintln(b"");
             kprint(b"> ");
             
-            input_len = 0;
+            input.clear();
         } else if ch == 8 {
             // Backspace: could handle cursor, for now just ignore
         } else {
             kprint(&[ch]);
-            if input_len < input.len() {
-                input[input_len] = ch;
-                input_len += 1;
-            }
+            input.push(ch);
         }
     }
 }
@@ -15,35 +15,40 @@ pub use string::memmove::*;
 pub use string::strlen::*;
 
 #[cfg(not(test))]
-use crate::stdio::printf::kprintln;
-#[cfg(not(test))]
-use crate::string::tostring::u32_to_str;
+use crate::stdio::printf::kprint;
 
+#[cfg(not(test))]
+use core::fmt::Write;
 #[cfg(not(test))]
 use core::panic::PanicInfo;
 
+/// `core::fmt` sink for the panic handler.  It formats straight to the
+/// terminal rather than through `alloc`, so out-of-memory panics still
+/// get reported.
+#[cfg(not(test))]
+struct PanicWriter;
+
+#[cfg(not(test))]
+impl Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        kprint(s.as_bytes());
+        Ok(())
+    }
+}
+
 #[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    kprintln(b"Kernel panic!!!");
-    if let Some(location) = _info.location() {
-        kprintln(b"At:");
-        kprintln(location.file().as_bytes());
-        kprintln(b":");
-        let mut line_buf = [0u8; 12];
-        let mut col_buf = [0u8; 12];
-
-        let line = location.line();
-        let col = location.column()
-        ;
-        // Convert line and column numbers to strings without using `alloc` or `core::fmt`.
-        let line_str = u32_to_str(line, &mut line_buf);
-        kprintln(line_str);
-        kprintln(b":");
-        let col_str = u32_to_str(col, &mut col_buf);
-        kprintln(col_str);
-    } else {
-        kprintln(b"Unknown location");
+fn panic(info: &PanicInfo) -> ! {
+    let mut out = PanicWriter;
+    let _ = writeln!(out, "Kernel panic!!!");
+    let _ = writeln!(out, "{}", info.message());
+    match info.location() {
+        Some(location) => {
+            let _ = writeln!(out, "At: {}:{}:{}", location.file(), location.line(), location.column());
+        }
+        None => {
+            let _ = writeln!(out, "Unknown location");
+        }
     }
     loop {
         core::hint::spin_loop();